@@ -0,0 +1,539 @@
+use std::borrow::Cow;
+
+/// Context lines around each hunk unless [`DiffOptions::context`] says
+/// otherwise, the same as git.
+pub const DEFAULT_CONTEXT_LINES: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Tag {
+    Equal,
+    Delete,
+    Insert,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DiffLine<'a> {
+    tag: Tag,
+    text: &'a str,
+}
+
+/// Knobs for how file diffs are computed.
+#[derive(Clone, Copy, Debug)]
+pub struct DiffOptions {
+    /// Compare lines with indentation, trailing whitespace and runs of
+    /// spaces ignored, so whitespace-only changes produce no hunks. Lines
+    /// are still printed as they are in the files.
+    pub ignore_whitespace: bool,
+    /// Unchanged lines shown before and after each change. Changes whose
+    /// context would overlap or touch share one hunk; `0` gives hunks of
+    /// changed lines only.
+    pub context: usize,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            ignore_whitespace: false,
+            context: DEFAULT_CONTEXT_LINES,
+        }
+    }
+}
+
+/// How far into a blob to look for a NUL byte when deciding if it is binary.
+const BINARY_SNIFF_LEN: usize = 8192;
+
+/// Treats content as binary when a NUL byte appears in its first 8KB, the
+/// same heuristic git uses.
+pub fn is_binary(content: &[u8]) -> bool {
+    content.iter().take(BINARY_SNIFF_LEN).any(|&byte| byte == 0)
+}
+
+/// Text view of a blob, or `None` for binary content. Invalid UTF-8 in a text
+/// file is replaced rather than rejected so diffs never fail on it.
+pub fn as_text(content: &[u8]) -> Option<Cow<'_, str>> {
+    if is_binary(content) {
+        None
+    } else {
+        Some(String::from_utf8_lossy(content))
+    }
+}
+
+/// Formats the unified diff of one file. `None` on either side means the file
+/// does not exist there, so every line shows up as added or deleted. Returns an
+/// empty string when both sides are identical. Binary content is reported
+/// without a line diff.
+pub fn file_diff(path: &str, old: Option<&[u8]>, new: Option<&[u8]>) -> String {
+    file_diff_with(path, old, new, &DiffOptions::default())
+}
+
+/// Like [`file_diff`], with `options` deciding how lines are compared. A file
+/// on both sides whose changes all disappear under `options` gives an empty
+/// string.
+pub fn file_diff_with(
+    path: &str,
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    options: &DiffOptions,
+) -> String {
+    if old == new {
+        return String::new();
+    }
+    let old_name: String = match old {
+        Some(_) => format!("a/{}", path),
+        None => "/dev/null".to_string(),
+    };
+    let new_name: String = match new {
+        Some(_) => format!("b/{}", path),
+        None => "/dev/null".to_string(),
+    };
+    let header: String = format!(
+        "diff --sgvcs a/{} b/{}\n--- {}\n+++ {}\n",
+        path, path, old_name, new_name
+    );
+    let old_text: Option<Cow<str>> = as_text(old.unwrap_or_default());
+    let new_text: Option<Cow<str>> = as_text(new.unwrap_or_default());
+    match (old_text, new_text) {
+        (Some(old_text), Some(new_text)) => {
+            let hunks: String = unified_diff_with(&old_text, &new_text, options);
+            if hunks.is_empty() && old.is_some() && new.is_some() {
+                return String::new();
+            }
+            format!("{}{}", header, hunks)
+        }
+        _ => format!("{}Binary files differ\n", header),
+    }
+}
+
+/// Header for a file moved without changes, which has no hunks.
+pub fn rename_header(old_path: &str, new_path: &str) -> String {
+    format!(
+        "diff --sgvcs a/{} b/{}\nsimilarity index 100%\nrename from {}\nrename to {}\n",
+        old_path, new_path, old_path, new_path
+    )
+}
+
+/// Computes the hunks (`@@` headers plus `+`/`-`/` ` lines) between two texts.
+pub fn unified_diff(old: &str, new: &str) -> String {
+    unified_diff_with(old, new, &DiffOptions::default())
+}
+
+/// Like [`unified_diff`], with `options` deciding how lines are compared.
+pub fn unified_diff_with(old: &str, new: &str, options: &DiffOptions) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let script: Vec<DiffLine> = if options.ignore_whitespace {
+        whitespace_insensitive_script(&old_lines, &new_lines)
+    } else {
+        edit_script(&old_lines, &new_lines)
+    };
+
+    let context: usize = options.context;
+    let mut output: String = String::new();
+    let mut old_pos: usize = 0;
+    let mut new_pos: usize = 0;
+    let mut i: usize = 0;
+    while i < script.len() {
+        if script[i].tag == Tag::Equal {
+            old_pos += 1;
+            new_pos += 1;
+            i += 1;
+            continue;
+        }
+
+        // Grow the hunk until the next change is further away than two
+        // context regions, so nearby changes share a single hunk
+        let mut end: usize = i;
+        loop {
+            while end < script.len() && script[end].tag != Tag::Equal {
+                end += 1;
+            }
+            let mut next: usize = end;
+            while next < script.len() && script[next].tag == Tag::Equal {
+                next += 1;
+            }
+            if next < script.len() && next - end <= 2 * context {
+                end = next;
+            } else {
+                break;
+            }
+        }
+
+        let leading: usize = i.min(context);
+        let start: usize = i - leading;
+        let stop: usize = (end + context).min(script.len());
+        let hunk: &[DiffLine] = &script[start..stop];
+        let old_count: usize = hunk.iter().filter(|line| line.tag != Tag::Insert).count();
+        let new_count: usize = hunk.iter().filter(|line| line.tag != Tag::Delete).count();
+        let old_start: usize = old_pos - leading;
+        let new_start: usize = new_pos - leading;
+
+        output.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk_start(old_start, old_count),
+            old_count,
+            hunk_start(new_start, new_count),
+            new_count
+        ));
+        for line in hunk {
+            let prefix: char = match line.tag {
+                Tag::Equal => ' ',
+                Tag::Delete => '-',
+                Tag::Insert => '+',
+            };
+            output.push(prefix);
+            output.push_str(line.text);
+            output.push('\n');
+        }
+
+        old_pos = old_start + old_count;
+        new_pos = new_start + new_count;
+        i = stop;
+    }
+    output
+}
+
+/// Line numbers in hunk headers are 1-based, except that an empty range names
+/// the line it follows (so an insertion at the top of a file is `-0,0`).
+fn hunk_start(start: usize, count: usize) -> usize {
+    if count > 0 {
+        start + 1
+    } else {
+        start
+    }
+}
+
+/// Longest-common-subsequence edit script between two line lists. The common
+/// prefix and suffix are stripped first so the quadratic table only covers the
+/// region that actually changed.
+fn edit_script<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<DiffLine<'a>> {
+    let prefix: usize = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix: usize = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a: &[&str] = &old[prefix..old.len() - suffix];
+    let b: &[&str] = &new[prefix..new.len() - suffix];
+
+    // lcs[i][j] is the LCS length of a[i..] and b[j..]
+    let width: usize = b.len() + 1;
+    let mut lcs: Vec<u32> = vec![0; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i * width + j] = if a[i] == b[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut script: Vec<DiffLine> = old[..prefix]
+        .iter()
+        .map(|text| DiffLine {
+            tag: Tag::Equal,
+            text,
+        })
+        .collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            script.push(DiffLine {
+                tag: Tag::Equal,
+                text: a[i],
+            });
+            i += 1;
+            j += 1;
+        } else if j == b.len()
+            || (i < a.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+        {
+            script.push(DiffLine {
+                tag: Tag::Delete,
+                text: a[i],
+            });
+            i += 1;
+        } else {
+            script.push(DiffLine {
+                tag: Tag::Insert,
+                text: b[j],
+            });
+            j += 1;
+        }
+    }
+    script.extend(old[old.len() - suffix..].iter().map(|text| DiffLine {
+        tag: Tag::Equal,
+        text,
+    }));
+    script
+}
+
+/// Edit script that treats lines differing only in whitespace as equal.
+/// Equal lines carry the text from `new`.
+fn whitespace_insensitive_script<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<DiffLine<'a>> {
+    let normalize = |line: &&str| line.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let old_keys: Vec<String> = old.iter().map(normalize).collect();
+    let new_keys: Vec<String> = new.iter().map(normalize).collect();
+    let old_refs: Vec<&str> = old_keys.iter().map(String::as_str).collect();
+    let new_refs: Vec<&str> = new_keys.iter().map(String::as_str).collect();
+    let (mut i, mut j) = (0, 0);
+    edit_script(&old_refs, &new_refs)
+        .into_iter()
+        .map(|line| {
+            let text: &str = match line.tag {
+                Tag::Equal => {
+                    i += 1;
+                    j += 1;
+                    new[j - 1]
+                }
+                Tag::Delete => {
+                    i += 1;
+                    old[i - 1]
+                }
+                Tag::Insert => {
+                    j += 1;
+                    new[j - 1]
+                }
+            };
+            DiffLine {
+                tag: line.tag,
+                text,
+            }
+        })
+        .collect()
+}
+
+/// For each line of `new`, the index of the line in `old` it was kept from,
+/// or `None` when the line is new. Used to follow lines back through history.
+pub fn line_origins(old: &str, new: &str) -> Vec<Option<usize>> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let mut origins: Vec<Option<usize>> = Vec::with_capacity(new_lines.len());
+    let mut old_pos: usize = 0;
+    for line in edit_script(&old_lines, &new_lines) {
+        match line.tag {
+            Tag::Equal => {
+                origins.push(Some(old_pos));
+                old_pos += 1;
+            }
+            Tag::Delete => old_pos += 1,
+            Tag::Insert => origins.push(None),
+        }
+    }
+    origins
+}
+
+/// One side's edit against the merge base: base lines `start..end` are
+/// replaced by `lines`. An insertion has `start == end`.
+#[derive(Debug)]
+struct Change<'a> {
+    start: usize,
+    end: usize,
+    lines: Vec<&'a str>,
+    ours: bool,
+}
+
+fn changes<'a>(base: &[&'a str], side: &[&'a str], ours: bool) -> Vec<Change<'a>> {
+    let mut result: Vec<Change> = Vec::new();
+    let mut pos: usize = 0;
+    let mut current: Option<Change> = None;
+    for line in edit_script(base, side) {
+        match line.tag {
+            Tag::Equal => {
+                result.extend(current.take());
+                pos += 1;
+            }
+            Tag::Delete => {
+                let change: &mut Change = current.get_or_insert_with(|| Change {
+                    start: pos,
+                    end: pos,
+                    lines: Vec::new(),
+                    ours,
+                });
+                pos += 1;
+                change.end = pos;
+            }
+            Tag::Insert => current
+                .get_or_insert_with(|| Change {
+                    start: pos,
+                    end: pos,
+                    lines: Vec::new(),
+                    ours,
+                })
+                .lines
+                .push(line.text),
+        }
+    }
+    result.extend(current);
+    result
+}
+
+/// Applies one side's changes within base lines `start..end`.
+fn apply<'a>(base: &[&'a str], start: usize, end: usize, changes: &[&Change<'a>]) -> Vec<&'a str> {
+    let mut lines: Vec<&str> = Vec::new();
+    let mut pos: usize = start;
+    for change in changes {
+        lines.extend_from_slice(&base[pos..change.start]);
+        lines.extend_from_slice(&change.lines);
+        pos = change.end;
+    }
+    lines.extend_from_slice(&base[pos..end]);
+    lines
+}
+
+/// Three-way merge of two texts that both descend from `base`. Changes made on
+/// only one side are taken as is; regions changed differently on both sides
+/// are wrapped in `<<<<<<<`/`=======`/`>>>>>>>` markers. Returns the merged
+/// text and whether any conflict was written.
+pub fn merge3(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    ours_label: &str,
+    theirs_label: &str,
+) -> (String, bool) {
+    let base_lines: Vec<&str> = base.lines().collect();
+    let ours_lines: Vec<&str> = ours.lines().collect();
+    let theirs_lines: Vec<&str> = theirs.lines().collect();
+    let mut all: Vec<Change> = changes(&base_lines, &ours_lines, true);
+    all.extend(changes(&base_lines, &theirs_lines, false));
+    all.sort_by_key(|change| (change.start, change.end));
+
+    let mut merged: String = String::new();
+    let mut conflicted: bool = false;
+    let mut pos: usize = 0;
+    let mut i: usize = 0;
+    while i < all.len() {
+        // Group every change whose base range overlaps or touches the group
+        let start: usize = all[i].start;
+        let mut end: usize = all[i].end;
+        let mut j: usize = i + 1;
+        while j < all.len() && all[j].start <= end {
+            end = end.max(all[j].end);
+            j += 1;
+        }
+        let group: &[Change] = &all[i..j];
+        let our_changes: Vec<&Change> = group.iter().filter(|change| change.ours).collect();
+        let their_changes: Vec<&Change> = group.iter().filter(|change| !change.ours).collect();
+
+        push_lines(&mut merged, &base_lines[pos..start]);
+        let our_side: Vec<&str> = apply(&base_lines, start, end, &our_changes);
+        let their_side: Vec<&str> = apply(&base_lines, start, end, &their_changes);
+        if their_changes.is_empty() || our_side == their_side {
+            push_lines(&mut merged, &our_side);
+        } else if our_changes.is_empty() {
+            push_lines(&mut merged, &their_side);
+        } else {
+            conflicted = true;
+            merged.push_str(&format!("<<<<<<< {}\n", ours_label));
+            push_lines(&mut merged, &our_side);
+            merged.push_str("=======\n");
+            push_lines(&mut merged, &their_side);
+            merged.push_str(&format!(">>>>>>> {}\n", theirs_label));
+        }
+        pos = end;
+        i = j;
+    }
+    push_lines(&mut merged, &base_lines[pos..]);
+    (merged, conflicted)
+}
+
+fn push_lines(output: &mut String, lines: &[&str]) {
+    for line in lines {
+        output.push_str(line);
+        output.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_diff_marks_changed_lines() {
+        let old: &str = "a\nb\nc\nd\ne\n";
+        let new: &str = "a\nb\nC\nd\ne\nf\n";
+        assert_eq!(
+            unified_diff(old, new),
+            "@@ -1,5 +1,6 @@\n a\n b\n-c\n+C\n d\n e\n+f\n"
+        );
+        assert_eq!(unified_diff(old, old), "");
+    }
+
+    #[test]
+    fn unified_diff_splits_distant_changes_into_hunks() {
+        let old: String = (1..=20).map(|n| format!("{}\n", n)).collect();
+        let new: String = old
+            .replacen("2\n", "two\n", 1)
+            .replace("19\n", "nineteen\n");
+        let diff: String = unified_diff(&old, &new);
+        assert_eq!(diff.matches("@@ ").count(), 2);
+        assert!(diff.starts_with("@@ -1,5 +1,5 @@\n 1\n-2\n+two\n"));
+        assert!(diff.contains("@@ -16,5 +16,5 @@\n"));
+    }
+
+    #[test]
+    fn file_diff_of_a_new_file_adds_every_line() {
+        assert_eq!(
+            file_diff("new.txt", None, Some(b"x\ny\n")),
+            "diff --sgvcs a/new.txt b/new.txt\n--- /dev/null\n+++ b/new.txt\n\
+             @@ -0,0 +1,2 @@\n+x\n+y\n"
+        );
+        assert_eq!(file_diff("same.txt", Some(b"x\n"), Some(b"x\n")), "");
+    }
+
+    #[test]
+    fn binary_content_is_not_line_diffed() {
+        assert!(is_binary(b"PNG\0\x01\x02"));
+        assert!(!is_binary("caf\u{e9}\n".as_bytes()));
+        assert!(as_text(b"a\0b").is_none());
+        // Bytes that are not valid UTF-8 still diff as text
+        assert_eq!(as_text(b"\xff\n").unwrap(), "\u{fffd}\n");
+
+        let diff: String = file_diff("logo.png", Some(b"\0old"), Some(b"\0new"));
+        assert!(diff.ends_with("+++ b/logo.png\nBinary files differ\n"));
+        assert!(!diff.contains("@@"));
+    }
+
+    const IGNORE_WHITESPACE: DiffOptions = DiffOptions {
+        ignore_whitespace: true,
+        context: DEFAULT_CONTEXT_LINES,
+    };
+
+    #[test]
+    fn whitespace_only_changes_can_be_ignored() {
+        let old: &str = "fn main() {\n    let x = 1;\n}\n";
+        for new in [
+            "fn main() {\n\tlet x = 1;\n}\n",
+            "fn main() {\n    let x = 1;   \n}\n",
+            "fn main() {\n    let  x  =  1;\n}\n",
+            "  fn main() {\n        let x = 1;\n}\n",
+        ] {
+            assert_eq!(unified_diff_with(old, new, &IGNORE_WHITESPACE), "");
+            assert_ne!(unified_diff(old, new), "");
+            let diff: String = file_diff_with(
+                "main.rs",
+                Some(old.as_bytes()),
+                Some(new.as_bytes()),
+                &IGNORE_WHITESPACE,
+            );
+            assert_eq!(diff, "");
+        }
+        // Whitespace inside a word still counts
+        assert_ne!(unified_diff_with("ab\n", "a b\n", &IGNORE_WHITESPACE), "");
+    }
+
+    #[test]
+    fn ignoring_whitespace_still_prints_the_real_lines() {
+        let old: &str = "a\n  b\nc\n";
+        let new: &str = "a\n\tb\nC\n";
+        assert_eq!(
+            unified_diff_with(old, new, &IGNORE_WHITESPACE),
+            "@@ -1,3 +1,3 @@\n a\n \tb\n-c\n+C\n"
+        );
+    }
+}
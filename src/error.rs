@@ -0,0 +1,139 @@
+use std::{fmt, io, path::PathBuf};
+
+/// Everything a repository operation can fail with, so callers can tell a
+/// missing object from a corrupt one or a plain I/O failure.
+#[derive(Debug)]
+pub enum SgvcsError {
+    /// Reading or writing the repo or working tree failed.
+    Io(io::Error),
+    /// Serializing or parsing repo metadata such as the index failed.
+    Json(serde_json::Error),
+    /// The path has no `.sgvcs` directory.
+    NotARepo(PathBuf),
+    /// No object or commit matches the given name.
+    ObjectNotFound(String),
+    /// An object is stored but its contents are not what they should be.
+    CorruptObject { hash: String, reason: String },
+    /// The index does not parse, or stages a blob that is not stored.
+    CorruptIndex(String),
+    /// A short hash matches more than one object.
+    Ambiguous {
+        prefix: String,
+        matches: Vec<String>,
+    },
+    /// Nothing is staged that differs from the parent commit.
+    NothingToCommit,
+    /// HEAD does not point at a commit yet: the current branch is unborn.
+    NoCommits,
+    /// The commit message is blank and the repo has a commit template,
+    /// returned here so the caller can fill it in.
+    EmptyMessage { template: String },
+    /// A ref, path or other named thing does not exist.
+    NotFound(String),
+    /// A ref or other named thing already exists.
+    AlreadyExists(String),
+    /// The arguments or repo state do not allow the operation.
+    InvalidInput(String),
+    /// Another process holds this lock file; it can be removed by hand if
+    /// that process died.
+    Locked(PathBuf),
+    /// The repo was written by a newer build with an on-disk format this one
+    /// does not understand.
+    IncompatibleRepo { found: u32, supported: u32 },
+}
+
+pub type Result<T> = std::result::Result<T, SgvcsError>;
+
+impl fmt::Display for SgvcsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SgvcsError::Io(err) => write!(f, "{}", err),
+            SgvcsError::Json(err) => write!(f, "{}", err),
+            SgvcsError::NotARepo(path) => write!(f, "{:?} is not a sgvcs repository", path),
+            SgvcsError::ObjectNotFound(name) => write!(f, "Object {:?} not found", name),
+            SgvcsError::CorruptObject { hash, reason } => {
+                write!(f, "Object {} is corrupt: {}", hash, reason)
+            }
+            SgvcsError::CorruptIndex(reason) => write!(f, "Index is corrupt: {}", reason),
+            SgvcsError::Ambiguous { prefix, matches } => write!(
+                f,
+                "Short hash {:?} is ambiguous: {}",
+                prefix,
+                matches.join(", ")
+            ),
+            SgvcsError::NothingToCommit => write!(f, "Nothing to commit: no staged changes"),
+            SgvcsError::NoCommits => write!(f, "No commits yet"),
+            SgvcsError::EmptyMessage { .. } => write!(
+                f,
+                "Empty commit message: fill in the commit template and try again"
+            ),
+            SgvcsError::NotFound(message)
+            | SgvcsError::AlreadyExists(message)
+            | SgvcsError::InvalidInput(message) => write!(f, "{}", message),
+            SgvcsError::Locked(path) => write!(
+                f,
+                "Unable to lock {:?}: another sgvcs process is using the repository",
+                path
+            ),
+            SgvcsError::IncompatibleRepo { found, supported } => write!(
+                f,
+                "Repository format version {} is newer than the supported version {}",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SgvcsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SgvcsError::Io(err) => Some(err),
+            SgvcsError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SgvcsError {
+    fn from(err: io::Error) -> Self {
+        SgvcsError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SgvcsError {
+    fn from(err: serde_json::Error) -> Self {
+        SgvcsError::Json(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn io_errors_convert_and_keep_their_source() {
+        let err: SgvcsError = io::Error::new(io::ErrorKind::PermissionDenied, "denied").into();
+        assert!(matches!(&err, SgvcsError::Io(io) if io.kind() == io::ErrorKind::PermissionDenied));
+        assert_eq!(err.to_string(), "denied");
+        assert!(err.source().is_some());
+        assert!(SgvcsError::NothingToCommit.source().is_none());
+    }
+
+    #[test]
+    fn messages_name_what_went_wrong() {
+        let ambiguous: SgvcsError = SgvcsError::Ambiguous {
+            prefix: "abcd".to_string(),
+            matches: vec!["abcd1".to_string(), "abcd2".to_string()],
+        };
+        assert_eq!(
+            ambiguous.to_string(),
+            "Short hash \"abcd\" is ambiguous: abcd1, abcd2"
+        );
+        let corrupt: SgvcsError = SgvcsError::CorruptObject {
+            hash: "abcd".to_string(),
+            reason: "bad".to_string(),
+        };
+        assert_eq!(corrupt.to_string(), "Object abcd is corrupt: bad");
+    }
+}
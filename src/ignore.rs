@@ -0,0 +1,117 @@
+use glob::{MatchOptions, Pattern};
+use std::path::{Component, Path};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Debug)]
+struct IgnoreRule {
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+/// Patterns parsed from a `.sgvcsignore` file, matched the way gitignore does:
+/// the last matching rule wins and a `!` rule re-includes a path.
+#[derive(Debug, Default)]
+pub struct IgnoreSet {
+    rules: Vec<IgnoreRule>,
+    warnings: Vec<String>,
+}
+
+impl IgnoreSet {
+    /// Invalid patterns are skipped, each leaving a line in
+    /// [`IgnoreSet::warnings`].
+    pub fn parse(contents: &str) -> IgnoreSet {
+        let mut rules: Vec<IgnoreRule> = Vec::new();
+        let mut warnings: Vec<String> = Vec::new();
+        for line in contents.lines() {
+            let line: &str = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            // A slash anywhere but the end ties the pattern to the repo root
+            let anchored: bool = line.contains('/');
+            let line: &str = line.trim_start_matches('/');
+            if line.is_empty() {
+                continue;
+            }
+            match Pattern::new(line) {
+                Ok(pattern) => rules.push(IgnoreRule {
+                    pattern,
+                    negated,
+                    dir_only,
+                    anchored,
+                }),
+                Err(err) => warnings.push(format!(
+                    "Skipping invalid ignore pattern {:?}: {}",
+                    line, err
+                )),
+            }
+        }
+        IgnoreSet { rules, warnings }
+    }
+
+    /// What [`IgnoreSet::parse`] found wrong with the file, for the caller to
+    /// report.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Checks a path relative to the repo root. A path inside an ignored
+    /// directory is ignored as well.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let components: Vec<&str> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+        (1..=components.len()).any(|end| {
+            let dir: bool = end < components.len() || is_dir;
+            self.matches(&components[..end], dir)
+        })
+    }
+
+    fn matches(&self, components: &[&str], is_dir: bool) -> bool {
+        let full: String = components.join("/");
+        let name: &str = components.last().copied().unwrap_or_default();
+        let mut ignored: bool = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let candidate: &str = if rule.anchored { &full } else { name };
+            if rule.pattern.matches_with(candidate, MATCH_OPTIONS) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_patterns_become_warnings() {
+        let ignore: IgnoreSet = IgnoreSet::parse("[\n*.log\n");
+        assert_eq!(ignore.warnings().len(), 1);
+        assert!(ignore.warnings()[0].contains("\"[\""));
+        assert!(ignore.is_ignored(Path::new("build.log"), false));
+    }
+}
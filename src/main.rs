@@ -1,29 +1,197 @@
-// use std::{path::Path, thread};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-// use std::time::Duration;
+use clap::{Parser, Subcommand};
+use diff::DiffOptions;
+use error::SgvcsError;
 use vcs::Sgvcs;
 
+pub mod diff;
+pub mod error;
+pub mod ignore;
+pub mod store;
 pub mod vcs;
 
+#[derive(Parser)]
+#[command(name = "sgvcs", about = "A small version control system")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a repository in the current directory
+    Init,
+    /// Stage files, or every file under a directory
+    Add {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Record the staged changes as a new commit
+    Commit {
+        #[arg(short, long)]
+        message: String,
+        /// Commit even if nothing changed since the parent
+        #[arg(long)]
+        allow_empty: bool,
+        /// Show what would be committed without committing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show history from HEAD, newest first
+    Log {
+        /// Show at most this many commits
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+        /// One line per commit
+        #[arg(long)]
+        oneline: bool,
+        /// Draw the history of every branch as a graph
+        #[arg(long, conflicts_with_all = ["limit", "oneline"])]
+        graph: bool,
+        /// Print the whole log as JSON
+        #[arg(long, conflicts_with_all = ["limit", "oneline", "graph"])]
+        json: bool,
+    },
+    /// Show staged, modified and untracked files
+    Status {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the changes a commit made, or the changes between two commits
+    Diff {
+        #[arg(default_value = "HEAD")]
+        from: String,
+        to: Option<String>,
+        /// Ignore changes in whitespace
+        #[arg(short = 'w', long)]
+        ignore_whitespace: bool,
+        /// Lines of context around each change
+        #[arg(short = 'U', long, default_value_t = diff::DEFAULT_CONTEXT_LINES)]
+        unified: usize,
+    },
+    /// Switch to a branch or commit
+    Checkout {
+        target: String,
+        /// Overwrite local changes
+        #[arg(short, long)]
+        force: bool,
+        /// Create a branch named `target` at HEAD and switch to it
+        #[arg(short = 'b', conflicts_with = "force")]
+        new_branch: bool,
+    },
+    /// Shelve local changes, or bring the newest shelved changes back
+    Stash {
+        #[command(subcommand)]
+        action: Option<StashAction>,
+    },
+}
+
+#[derive(Subcommand)]
+enum StashAction {
+    /// Save local changes and reset to HEAD (the default)
+    Push,
+    /// Reapply the newest stash entry and drop it
+    Pop,
+    /// Show the stash entries, newest first
+    List,
+}
+
 #[tokio::main]
-async fn main() {
-    let sgvcs: Result<Sgvcs, std::io::Error> = Sgvcs::new_async().await;
-    println!("{:?}", sgvcs);
-    match sgvcs {
-        Ok(sgvcs) => {
-            let mut sgvcs: Sgvcs = sgvcs;
-            // sgvcs.add_file(&Path::new("src/sample.txt")).await;
-            // sgvcs.commit("Initial Commit".to_string()).await;
-            // thread::sleep(Duration::from_secs(1));
-            // sgvcs.add_file(&Path::new("src/sample.txt")).await;
-            // sgvcs.commit("Second Commit".to_string()).await;
-            // thread::sleep(Duration::from_secs(1));
-            // sgvcs.add_file(&Path::new("src/sample.txt")).await;
-            // sgvcs.add_file(&Path::new("src/sample2.txt")).await;
-            // sgvcs.commit("Third Commit".to_string()).await;
-            sgvcs.log().await;
-            sgvcs.show_commit_diff("53d4e91b205a6448cc644193b353768e783dc5f0".to_string()).await;
+async fn main() -> ExitCode {
+    let cli: Cli = Cli::parse();
+    match run(cli.command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(command: Command) -> Result<(), SgvcsError> {
+    // Only `init` creates a repo; everything else needs an existing one
+    if let Command::Init = command {
+        Sgvcs::new_async().await?;
+        return Ok(());
+    }
+    let mut sgvcs: Sgvcs = Sgvcs::open().await?;
+    match command {
+        Command::Init => unreachable!("init returns above"),
+        Command::Add { paths } => {
+            for path in paths {
+                if path.is_dir() {
+                    sgvcs.add_dir(&path).await?;
+                } else {
+                    sgvcs.add_file(&path).await?;
+                }
+            }
+        }
+        Command::Commit {
+            message,
+            allow_empty,
+            dry_run,
+        } => {
+            if dry_run {
+                sgvcs.commit_dry_run(message, allow_empty).await?;
+            } else {
+                sgvcs.commit(message, allow_empty).await?;
+            }
+        }
+        Command::Log {
+            limit,
+            oneline,
+            graph,
+            json,
+        } => {
+            if json {
+                println!("{}", sgvcs.log_json().await?);
+            } else if graph {
+                print!("{}", sgvcs.log_graph().await?);
+            } else {
+                sgvcs.log(limit, oneline).await?;
+            }
+        }
+        Command::Status { json: true } => println!("{}", sgvcs.status_json().await?),
+        Command::Status { json: false } => sgvcs.status().await?,
+        Command::Diff {
+            from,
+            to,
+            ignore_whitespace,
+            unified,
+        } => {
+            let options: DiffOptions = DiffOptions {
+                ignore_whitespace,
+                context: unified,
+            };
+            let diff: String = match to {
+                Some(to) => sgvcs.diff_commits_with(&from, &to, &options).await?,
+                None => sgvcs.diff_commit_with(&from, &options).await?,
+            };
+            print!("{}", diff);
+        }
+        Command::Checkout {
+            target,
+            force,
+            new_branch,
+        } => {
+            if new_branch {
+                sgvcs.checkout_new_branch(&target).await?;
+            } else {
+                sgvcs.checkout(&target, force).await?;
+            }
         }
-        Err(err) => println!("{:?}", err),
+        Command::Stash { action } => match action.unwrap_or(StashAction::Push) {
+            StashAction::Push => sgvcs.stash_push().await?,
+            StashAction::Pop => sgvcs.stash_pop().await?,
+            StashAction::List => {
+                for (n, entry) in sgvcs.stash_list().await?.iter().enumerate() {
+                    println!("stash@{{{}}}: {}", n, entry.message);
+                }
+            }
+        },
     }
+    Ok(())
 }
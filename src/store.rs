@@ -0,0 +1,377 @@
+use crate::error::{Result, SgvcsError};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// What the [`ObjectStore`] methods return: trait objects cannot have async
+/// methods, so each one hands back a boxed future instead.
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Where a repo keeps its objects. Objects are handed over and returned as
+/// stored, already compressed with their header, so a store only moves
+/// bytes around by hash. [`FsObjectStore`] is what every repo on disk uses;
+/// [`MemoryObjectStore`] keeps objects in memory. Only objects are pluggable:
+/// refs, `HEAD`, the index and the config always live in the repo directory.
+pub trait ObjectStore: fmt::Debug + Send + Sync {
+    /// The stored bytes of object `hash`, or [`SgvcsError::ObjectNotFound`].
+    fn read<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, Vec<u8>>;
+
+    /// Stores `bytes` as object `hash`, replacing any object of that name.
+    fn write<'a>(&'a self, hash: &'a str, bytes: &'a [u8]) -> StoreFuture<'a, ()>;
+
+    /// Deletes object `hash`.
+    fn remove<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, ()>;
+
+    /// Size in bytes of object `hash` as stored.
+    fn size<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, u64>;
+
+    /// Whether an object named `hash` (a full hash) is stored.
+    fn contains(&self, hash: &str) -> bool;
+
+    /// Hashes of every stored object that start with `prefix`, unsorted.
+    fn with_prefix<'a>(&'a self, prefix: &'a str) -> StoreFuture<'a, Vec<String>>;
+
+    /// Yields the hash of every stored object, in no particular order.
+    fn hashes(&self) -> Box<dyn Iterator<Item = Result<String>> + '_>;
+
+    /// The directory holding the object files, for the few operations that
+    /// work on them directly: streaming large blobs in, moving objects out of
+    /// the flat layout and cloning. `None` for a store not on disk.
+    fn dir(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Whether `name` could be an object name from either hash algorithm.
+pub(crate) fn is_object_name(name: &str) -> bool {
+    (name.len() == 40 || name.len() == 64) && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Objects live at `objects/<first two hex chars>/<rest>`, like git, so no
+/// single directory grows too large.
+#[derive(Debug, Clone)]
+pub struct FsObjectStore {
+    dir: PathBuf,
+}
+
+impl FsObjectStore {
+    pub fn new(dir: PathBuf) -> FsObjectStore {
+        FsObjectStore { dir }
+    }
+
+    /// Path of the file for object `hash`, which may not exist.
+    pub(crate) fn object_path(&self, hash: &str) -> PathBuf {
+        if hash.len() <= 2 {
+            return self.dir.join(hash);
+        }
+        let (shard, rest) = hash.split_at(2);
+        self.dir.join(shard).join(rest)
+    }
+}
+
+impl ObjectStore for FsObjectStore {
+    fn read<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            match fs::read(self.object_path(hash)).await {
+                Ok(stored) => Ok(stored),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    Err(SgvcsError::ObjectNotFound(hash.to_string()))
+                }
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    /// Writes a temporary file in the store and renames it into place, so an
+    /// interrupted write never leaves a truncated object behind. The
+    /// temporary file stays in the store since it may be on another disk
+    /// than the repo.
+    fn write<'a>(&'a self, hash: &'a str, bytes: &'a [u8]) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let object_path: PathBuf = self.object_path(hash);
+            if let Some(shard) = object_path.parent() {
+                fs::create_dir_all(shard).await?;
+            }
+            // Two writers of one object must not share a temporary file
+            static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+            let temp_path: PathBuf = self.dir.join(format!(
+                "tmp_{}_{}_{}",
+                hash,
+                std::process::id(),
+                TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut temp: fs::File = fs::File::create(&temp_path).await?;
+            temp.write_all(bytes).await?;
+            temp.sync_all().await?;
+            drop(temp);
+            if let Err(err) = fs::rename(&temp_path, &object_path).await {
+                let _ = fs::remove_file(&temp_path).await;
+                return Err(err.into());
+            }
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let path: PathBuf = self.object_path(hash);
+            fs::remove_file(&path).await?;
+            if let Some(shard) = path.parent() {
+                // Only succeeds once the shard is empty
+                let _ = fs::remove_dir(shard).await;
+            }
+            Ok(())
+        })
+    }
+
+    fn size<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, u64> {
+        Box::pin(async move { Ok(fs::metadata(self.object_path(hash)).await?.len()) })
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.object_path(hash).is_file()
+    }
+
+    fn with_prefix<'a>(&'a self, prefix: &'a str) -> StoreFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let (shard, rest) = prefix.split_at(2.min(prefix.len()));
+            let mut matches: Vec<String> = Vec::new();
+            match fs::read_dir(self.dir.join(shard)).await {
+                Ok(mut entries) => {
+                    while let Some(entry) = entries.next_entry().await? {
+                        let name: String = entry.file_name().to_string_lossy().to_string();
+                        if name.starts_with(rest) {
+                            matches.push(format!("{}{}", shard, name));
+                        }
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            Ok(matches)
+        })
+    }
+
+    /// Reads one shard directory at a time rather than listing the whole
+    /// store up front. Anything whose name is not a hash, such as a temporary
+    /// file, is skipped. The directory reads block.
+    fn hashes(&self) -> Box<dyn Iterator<Item = Result<String>> + '_> {
+        let (shards, error): (Option<std::fs::ReadDir>, Option<SgvcsError>) =
+            match std::fs::read_dir(&self.dir) {
+                Ok(shards) => (Some(shards), None),
+                Err(err) if err.kind() == ErrorKind::NotFound => (None, None),
+                Err(err) => (None, Some(err.into())),
+            };
+        Box::new(error.map(Err).into_iter().chain(ObjectIter {
+            shards,
+            current: None,
+        }))
+    }
+
+    fn dir(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+}
+
+/// Iterator behind [`FsObjectStore::hashes`]: walks the shard directories and
+/// the objects inside the current one.
+struct ObjectIter {
+    /// `None` once every shard has been read, or when there is no store.
+    shards: Option<std::fs::ReadDir>,
+    current: Option<(String, std::fs::ReadDir)>,
+}
+
+impl ObjectIter {
+    fn next_hash(&mut self) -> Result<Option<String>> {
+        loop {
+            if let Some((prefix, objects)) = &mut self.current {
+                match objects.next().transpose()? {
+                    Some(object) => {
+                        let hash: String =
+                            format!("{}{}", prefix, object.file_name().to_string_lossy());
+                        if is_object_name(&hash) {
+                            return Ok(Some(hash));
+                        }
+                        continue;
+                    }
+                    None => self.current = None,
+                }
+            }
+            let Some(shards) = &mut self.shards else {
+                return Ok(None);
+            };
+            match shards.next().transpose()? {
+                Some(shard) => {
+                    let prefix: String = shard.file_name().to_string_lossy().to_string();
+                    if prefix.len() == 2 && shard.file_type()?.is_dir() {
+                        self.current = Some((prefix, std::fs::read_dir(shard.path())?));
+                    }
+                }
+                None => self.shards = None,
+            }
+        }
+    }
+}
+
+impl Iterator for ObjectIter {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        let next: Result<Option<String>> = self.next_hash();
+        if next.is_err() {
+            // Stop after reporting a failed read instead of retrying it
+            self.shards = None;
+            self.current = None;
+        }
+        next.transpose()
+    }
+}
+
+/// Keeps objects in a map for the life of the store, for tests and other
+/// short-lived repos that need not touch the disk for objects. Refs, the
+/// index and the config of a repo using it are still files.
+#[derive(Debug, Default)]
+pub struct MemoryObjectStore {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryObjectStore {
+    pub fn new() -> MemoryObjectStore {
+        MemoryObjectStore::default()
+    }
+
+    fn objects(&self) -> MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.objects.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get(&self, hash: &str) -> Result<Vec<u8>> {
+        self.objects()
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| SgvcsError::ObjectNotFound(hash.to_string()))
+    }
+}
+
+impl ObjectStore for MemoryObjectStore {
+    fn read<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, Vec<u8>> {
+        Box::pin(async move { self.get(hash) })
+    }
+
+    fn write<'a>(&'a self, hash: &'a str, bytes: &'a [u8]) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            self.objects().insert(hash.to_string(), bytes.to_vec());
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            match self.objects().remove(hash) {
+                Some(_) => Ok(()),
+                None => Err(SgvcsError::ObjectNotFound(hash.to_string())),
+            }
+        })
+    }
+
+    fn size<'a>(&'a self, hash: &'a str) -> StoreFuture<'a, u64> {
+        Box::pin(async move { Ok(self.get(hash)?.len() as u64) })
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.objects().contains_key(hash)
+    }
+
+    fn with_prefix<'a>(&'a self, prefix: &'a str) -> StoreFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            Ok(self
+                .objects()
+                .keys()
+                .filter(|hash| hash.starts_with(prefix))
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn hashes(&self) -> Box<dyn Iterator<Item = Result<String>> + '_> {
+        let hashes: Vec<String> = self.objects().keys().cloned().collect();
+        Box::new(hashes.into_iter().map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+    const OTHER: &str = "01ffffffffffffffffffffffffffffffffffffff";
+
+    async fn exercise(store: &dyn ObjectStore) {
+        assert!(!store.contains(HASH));
+        assert!(matches!(
+            store.read(HASH).await,
+            Err(SgvcsError::ObjectNotFound(_))
+        ));
+
+        store.write(HASH, b"first").await.unwrap();
+        store.write(HASH, b"stored").await.unwrap();
+        store.write(OTHER, b"other").await.unwrap();
+        assert!(store.contains(HASH));
+        assert_eq!(store.read(HASH).await.unwrap(), b"stored");
+        assert_eq!(store.size(HASH).await.unwrap(), 6);
+
+        let mut matches: Vec<String> = store.with_prefix("01").await.unwrap();
+        matches.sort();
+        assert_eq!(matches, vec![HASH.to_string(), OTHER.to_string()]);
+        assert_eq!(store.with_prefix("0123").await.unwrap(), vec![HASH]);
+        assert!(store.with_prefix("ff").await.unwrap().is_empty());
+
+        let mut hashes: Vec<String> = store.hashes().collect::<Result<_>>().unwrap();
+        hashes.sort();
+        assert_eq!(hashes, vec![HASH.to_string(), OTHER.to_string()]);
+
+        store.remove(HASH).await.unwrap();
+        assert!(!store.contains(HASH));
+        assert!(store.remove(HASH).await.is_err());
+        assert_eq!(store.hashes().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_store_round_trips_objects() {
+        exercise(&MemoryObjectStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn fs_store_round_trips_objects() {
+        let dir: tempfile::TempDir = tempfile::tempdir().unwrap();
+        let store: FsObjectStore = FsObjectStore::new(dir.path().join("objects"));
+        assert_eq!(store.hashes().count(), 0);
+        exercise(&store).await;
+        assert!(store.object_path(OTHER).is_file());
+        // Temporary files and stray names are not objects
+        fs::write(dir.path().join("objects").join("01").join("junk"), b"")
+            .await
+            .unwrap();
+        assert_eq!(store.hashes().count(), 1);
+    }
+
+    #[test]
+    fn object_paths_are_sharded_by_the_first_two_characters() {
+        let store: FsObjectStore = FsObjectStore::new(PathBuf::from("/repo/objects"));
+        assert_eq!(
+            store.object_path(HASH),
+            Path::new("/repo/objects/01").join(&HASH[2..])
+        );
+        assert_eq!(store.object_path("ab"), Path::new("/repo/objects/ab"));
+    }
+}
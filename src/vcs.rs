@@ -1,283 +1,6856 @@
-use chrono::Utc;
-use serde::{Deserialize, Serialize};
+use crate::diff::{self, DiffOptions};
+use crate::error::{Result, SgvcsError};
+use crate::ignore::IgnoreSet;
+use crate::store::{FsObjectStore, ObjectStore};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use std::{
-    fmt::{self, Debug},
-    io::Result,
-    path::{Path, PathBuf},
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{hash_map::Entry, BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
+    fmt,
+    io::{ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Mutex, MutexGuard, PoisonError,
+    },
 };
-use tokio::io::AsyncReadExt;
-use tokio::{fs, io::AsyncWriteExt};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// Writes a line of command output through [`Sgvcs::emit`].
+macro_rules! say {
+    ($repo:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {
+        $repo.emit(format_args!(concat!($fmt, "\n") $(, $arg)*))
+    };
+}
 
 #[derive(Debug)]
 pub struct Sgvcs {
+    root_path: PathBuf,
     repo_path: PathBuf,
-    objects_path: PathBuf,
+    objects: Box<dyn ObjectStore>,
     index_path: PathBuf,
     head_path: PathBuf,
+    heads_path: PathBuf,
+    tags_path: PathBuf,
+    config_path: PathBuf,
+    reflog_path: PathBuf,
+    description_path: PathBuf,
+    stash_path: PathBuf,
+    /// A bare repo keeps the repo contents directly in `root_path` and has no
+    /// working tree, so commands that touch one refuse to run.
+    bare: bool,
+    hash_algo: HashAlgo,
+    /// Merge bases already computed, keyed by the two commit hashes. Commits
+    /// never change, so entries stay valid for the life of the handle.
+    merge_bases: Mutex<HashMap<(String, String), Option<String>>>,
+    output: Output,
+    prepare_commit_msg: MessageHook,
+    temp_names: TempNames,
+}
+
+/// Where command output goes: stdout unless replaced with
+/// [`Sgvcs::set_output`].
+struct Output(Mutex<Box<dyn Write + Send>>);
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Output")
+    }
+}
+
+/// Rewrites a commit message before it is recorded, registered with
+/// [`Sgvcs::set_prepare_commit_msg`].
+struct MessageHook(Mutex<Option<MessageFn>>);
+
+type MessageFn = Box<dyn Fn(&str) -> String + Send>;
+
+/// Hands out temporary file names no other write can be using: the process
+/// id keeps processes apart, the handle number keeps handles in one process
+/// apart, and the count keeps concurrent writes through one handle apart.
+#[derive(Debug)]
+struct TempNames {
+    handle: u64,
+    count: AtomicU64,
+}
+
+impl TempNames {
+    fn new() -> TempNames {
+        static HANDLES: AtomicU64 = AtomicU64::new(0);
+        TempNames {
+            handle: HANDLES.fetch_add(1, AtomicOrdering::Relaxed),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn next(&self, stem: &str) -> String {
+        self.name(stem, self.count.fetch_add(1, AtomicOrdering::Relaxed))
+    }
+
+    fn name(&self, stem: &str, count: u64) -> String {
+        format!(
+            "tmp_{}_{}_{}_{}",
+            stem,
+            std::process::id(),
+            self.handle,
+            count
+        )
+    }
+}
+
+impl fmt::Debug for MessageHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MessageHook")
+    }
+}
+
+const DEFAULT_BRANCH: &str = "main";
+const HASH_ALGO_KEY: &str = "core.hashalgo";
+const FORMAT_VERSION_KEY: &str = "core.formatversion";
+const CREATED_KEY: &str = "core.created";
+const BARE_KEY: &str = "core.bare";
+/// Line-ending conversion for text files, see [`AutoCrlf`].
+const AUTOCRLF_KEY: &str = "core.autocrlf";
+/// How many reflog and stash entries [`Sgvcs::prune`] keeps.
+const REFLOG_KEEP_KEY: &str = "gc.reflogkeep";
+const STASH_KEEP_KEY: &str = "gc.stashkeep";
+const DEFAULT_REFLOG_KEEP: usize = 100;
+const DEFAULT_STASH_KEEP: usize = 20;
+/// Absolute path of an object store kept outside `.sgvcs`.
+const OBJECTS_DIR_KEY: &str = "core.objectsdir";
+/// Start of HEAD when it is symbolic, followed by the branch name.
+const HEAD_REF_PREFIX: &str = "ref: refs/heads/";
+/// Marks the optional branch hint at the end of a reflog line.
+const REFLOG_BRANCH_PREFIX: &str = "branch=";
+/// Version of the on-disk layout written by this build. Bump it whenever the
+/// layout changes in a way older builds cannot read. Version 2 added the
+/// `<type> <len>\0` header to objects.
+const FORMAT_VERSION: u32 = 2;
+/// Lock file guarding the index, relative to the repo directory.
+const INDEX_LOCK_FILE: &str = "index.lock";
+/// State of a merge stopped by conflicts, relative to the repo directory: the
+/// commit being merged and the conflicted paths, one per line.
+const MERGE_HEAD_FILE: &str = "MERGE_HEAD";
+const MERGE_CONFLICTS_FILE: &str = "MERGE_CONFLICTS";
+/// Bisect progress, relative to the repo directory.
+const BISECT_FILE: &str = "BISECT";
+/// Optional message template, relative to the repo directory.
+const COMMIT_TEMPLATE_FILE: &str = "commit-template";
+const DEFAULT_DESCRIPTION: &str = "Unnamed repository; edit .sgvcs/description to name it.\n";
+const MIN_ABBREV: usize = 4;
+/// What a clone copies from the source's repo directory besides its objects.
+const CLONED_REPO_FILES: [&str; 4] = ["refs", "HEAD", "config", "description"];
+/// How many characters abbreviated hashes start at, see [`Sgvcs::short_hash`].
+const ABBREV_KEY: &str = "core.abbrev";
+const DEFAULT_ABBREV: usize = 7;
+/// Longest `<type> <len>\0` header an object can start with.
+const HEADER_MAX_LEN: usize = 32;
+/// Read size used when streaming file content into the object store.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Format of `time_stamp`, which older commits used as their only date.
+const DISPLAY_TIME_FORMAT: &str = "%d-%m-%Y %H:%M:%S";
+
+/// Hash function used for object names. Chosen when a repo is created and
+/// recorded as `core.hashalgo`; repos without the setting use SHA-1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgo {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgo {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Sha1 => "sha1",
+            HashAlgo::Sha256 => "sha256",
+        }
+    }
+
+    fn parse(name: &str) -> Result<HashAlgo> {
+        match name {
+            "sha1" => Ok(HashAlgo::Sha1),
+            "sha256" => Ok(HashAlgo::Sha256),
+            _ => Err(SgvcsError::InvalidInput(format!(
+                "Unknown {} {:?}",
+                HASH_ALGO_KEY, name
+            ))),
+        }
+    }
+}
+
+/// Line-ending conversion set by `core.autocrlf`. With `input` or `true`,
+/// CRLF in text files is stored as LF, so a file hashes the same whichever
+/// ending it was written with; `true` also writes CRLF when checking files
+/// out. Binary files are never converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum AutoCrlf {
+    #[default]
+    False,
+    Input,
+    True,
+}
+
+impl AutoCrlf {
+    fn parse(value: &str) -> Result<AutoCrlf> {
+        match value {
+            "false" => Ok(AutoCrlf::False),
+            "input" => Ok(AutoCrlf::Input),
+            "true" => Ok(AutoCrlf::True),
+            _ => Err(SgvcsError::InvalidInput(format!(
+                "{} must be true, false or input, not {:?}",
+                AUTOCRLF_KEY, value
+            ))),
+        }
+    }
+
+    /// Working-tree content as it should be stored.
+    fn clean(self, content: &[u8]) -> Cow<'_, [u8]> {
+        if self == AutoCrlf::False || diff::is_binary(content) || !content.contains(&b'\r') {
+            return Cow::Borrowed(content);
+        }
+        let mut cleaned: Vec<u8> = Vec::with_capacity(content.len());
+        for (i, &byte) in content.iter().enumerate() {
+            if !(byte == b'\r' && content.get(i + 1) == Some(&b'\n')) {
+                cleaned.push(byte);
+            }
+        }
+        Cow::Owned(cleaned)
+    }
+
+    /// Stored content as it should be written to the working tree.
+    fn smudge(self, content: &[u8]) -> Cow<'_, [u8]> {
+        if self != AutoCrlf::True || diff::is_binary(content) || !content.contains(&b'\n') {
+            return Cow::Borrowed(content);
+        }
+        let mut smudged: Vec<u8> = Vec::with_capacity(content.len() + content.len() / 16);
+        for (i, &byte) in content.iter().enumerate() {
+            if byte == b'\n' && (i == 0 || content[i - 1] != b'\r') {
+                smudged.push(b'\r');
+            }
+            smudged.push(byte);
+        }
+        Cow::Owned(smudged)
+    }
 }
 
+/// A tracked file: a path and its blob. In the index an entry with an empty
+/// hash stages the path's deletion instead.
 #[derive(Serialize, Deserialize, Debug)]
 struct IndexData {
     path: String,
     hash: String,
+    /// Unix permission bits, either `DEFAULT_MODE` or `EXECUTABLE_MODE`.
+    #[serde(default = "default_mode")]
+    mode: u32,
+}
+
+impl IndexData {
+    fn deletion(path: String) -> IndexData {
+        IndexData {
+            path,
+            hash: String::new(),
+            mode: DEFAULT_MODE,
+        }
+    }
+
+    fn is_deletion(&self) -> bool {
+        self.hash.is_empty()
+    }
+}
+
+const DEFAULT_MODE: u32 = 0o644;
+const EXECUTABLE_MODE: u32 = 0o755;
+
+fn default_mode() -> u32 {
+    DEFAULT_MODE
 }
 
-#[derive(Serialize, Deserialize)]
-struct CommitData {
+fn is_default_mode(mode: &u32) -> bool {
+    *mode == DEFAULT_MODE
+}
+
+/// A commit object as stored in the repo.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitData {
     message: String,
+    /// Human-readable UTC time, kept for display.
     time_stamp: String,
+    /// RFC3339 UTC time. Missing on commits written before it was added.
+    #[serde(default)]
+    date: String,
+    /// Flat list of tracked files. Commits now store their files as a tree
+    /// object, so this is rebuilt from `tree` on load and only read from disk
+    /// for commits written before trees existed.
+    #[serde(default, skip_serializing)]
     files: Vec<IndexData>,
     parent: String,
+    #[serde(default)]
+    author: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    tree: String,
+    /// Every parent of a merge commit, first parent first. `parent` still holds
+    /// the first parent so ordinary history walks stay unchanged.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    parents: Vec<String>,
 }
 
-impl Debug for CommitData {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "message: {:?}\ntime_stamp: {:?}\n",
-            self.message, self.time_stamp
-        )
-    }
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum EntryKind {
+    Blob,
+    Tree,
 }
 
-impl Sgvcs {
-    pub async fn new_async() -> std::io::Result<Sgvcs> {
-        let sgvcs: Sgvcs = Sgvcs::new();
-        sgvcs.init().await?;
-        Ok(sgvcs)
-    }
+/// One entry of a tree object: a file (blob) or subdirectory (tree) by name.
+#[derive(Serialize, Deserialize, Debug)]
+struct TreeEntry {
+    name: String,
+    kind: EntryKind,
+    hash: String,
+    /// Only written for executable files, so trees of ordinary files hash the
+    /// same as before modes were tracked.
+    #[serde(default = "default_mode", skip_serializing_if = "is_default_mode")]
+    mode: u32,
+}
 
-    pub fn new() -> Sgvcs {
-        let curr_dir: PathBuf = std::env::current_dir().expect("Cannot get current directory");
-        let repo_path: PathBuf = curr_dir.join(".sgvcs");
-        let objects_path: PathBuf = repo_path.join("objects");
-        let index_path: PathBuf = repo_path.join("index");
-        let head_path: PathBuf = repo_path.join("HEAD");
+enum TreeNode {
+    Blob(String, u32),
+    Dir(BTreeMap<String, TreeNode>),
+}
 
-        Sgvcs {
-            repo_path,
-            objects_path,
-            index_path,
-            head_path,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    Blob,
+    Tree,
+    Commit,
+}
+
+impl ObjectType {
+    /// Name used in object headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectType::Blob => "blob",
+            ObjectType::Tree => "tree",
+            ObjectType::Commit => "commit",
         }
     }
 
-    pub async fn init(&self) -> Result<()> {
-        if !self.repo_path.exists() {
-            fs::create_dir_all(&self.repo_path).await?;
-            println!("Created repo directory: {:?}", self.repo_path);
-        } else {
-            println!("Repo directory already exists: {:?}", self.repo_path);
+    fn parse(name: &[u8]) -> Option<ObjectType> {
+        match name {
+            b"blob" => Some(ObjectType::Blob),
+            b"tree" => Some(ObjectType::Tree),
+            b"commit" => Some(ObjectType::Commit),
+            _ => None,
         }
+    }
 
-        // Create the objects directory if it does not exist
-        if !self.objects_path.exists() {
-            fs::create_dir_all(&self.objects_path).await?;
-            println!("Created objects directory: {:?}", self.objects_path);
-        } else {
-            println!("Objects directory already exists: {:?}", self.objects_path);
-        }
+    /// The `<type> <len>\0` header stored in front of an object's content.
+    fn header(self, len: u64) -> Vec<u8> {
+        format!("{} {}\0", self.name(), len).into_bytes()
+    }
 
-        // Create the index file and write an empty array if it does not exist
-        if !self.index_path.exists() {
-            let mut index_file: fs::File = fs::File::create(&self.index_path).await?;
-            index_file.write_all(b"[]").await?;
-            println!("Created index file with empty array: {:?}", self.index_path);
-        } else {
-            println!("Index file already exists: {:?}", self.index_path);
+    /// Splits a stored object into its type and the offset its content
+    /// starts at. Objects from before headers were added come back with no
+    /// type and the whole object as content.
+    fn split_header(stored: &[u8]) -> (Option<ObjectType>, usize) {
+        match ObjectType::parse_header(stored) {
+            Some((kind, len, offset)) if len == stored.len() - offset => (Some(kind), offset),
+            _ => (None, 0),
         }
+    }
 
-        // Create the HEAD file if it does not exist
-        if !self.head_path.exists() {
-            fs::File::create(&self.head_path).await?;
-            println!("Created HEAD file: {:?}", self.head_path);
+    /// Guesses the type of an object stored without a header from what its
+    /// contents deserialize as. Anything neither a commit nor a tree is a
+    /// blob.
+    fn sniff(content: &[u8]) -> ObjectType {
+        if serde_json::from_slice::<CommitData>(content).is_ok() {
+            ObjectType::Commit
+        } else if serde_json::from_slice::<Vec<TreeEntry>>(content).is_ok() {
+            ObjectType::Tree
         } else {
-            println!("HEAD file already exists: {:?}", self.head_path);
+            ObjectType::Blob
         }
+    }
 
-        Ok(())
+    /// Reads the type, declared content length and content offset from the
+    /// start of a stored object, without checking the length against the
+    /// content that follows.
+    fn parse_header(stored: &[u8]) -> Option<(ObjectType, usize, usize)> {
+        let nul: usize = stored
+            .iter()
+            .take(HEADER_MAX_LEN)
+            .position(|&byte| byte == 0)?;
+        let header: &[u8] = &stored[..nul];
+        let space: usize = header.iter().position(|&byte| byte == b' ')?;
+        let len: usize = std::str::from_utf8(&header[space + 1..])
+            .ok()?
+            .parse()
+            .ok()?;
+        Some((ObjectType::parse(&header[..space])?, len, nul + 1))
     }
+}
 
-    pub async fn add_file(&mut self, path: &Path) {
-        println!("{:?}", path);
-        let mut file: fs::File = fs::File::open(path).await.unwrap();
-        let mut content: Vec<u8> = Vec::new();
-        file.read_to_end(&mut content).await.unwrap();
-        let hashed_data: String = Self::hash(content.as_slice());
-        let object_path: PathBuf = self.objects_path.join(hashed_data.clone());
-        if !object_path.exists() {
-            let mut object_file: fs::File = fs::File::create(&object_path).await.unwrap();
-            object_file.write_all(content.as_slice()).await.unwrap();
-        } else {
-            let mut object_file: fs::File = fs::File::open(&object_path).await.unwrap();
-            object_file.write_all(content.as_slice()).await.unwrap();
-        }
-        self.update_staging_area(path, hashed_data.clone()).await;
-        println!("Added {:?} to index", path);
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub hash: String,
+    pub message: String,
+    pub timestamp: String,
+    /// Parsed commit time, `None` only when the stored date is unreadable.
+    pub time: Option<DateTime<Utc>>,
+    pub parent: String,
+    pub author: String,
+    pub email: String,
+}
+
+/// Whether [`Sgvcs::init`] set up a new repo or found one already there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitOutcome {
+    Created,
+    AlreadyExists,
+}
+
+/// What a commit would record, from [`Sgvcs::commit_dry_run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitPreview {
+    /// The message after the prepare-commit-msg hook.
+    pub message: String,
+    pub parents: Vec<String>,
+    /// Staged paths that differ from the parent commit, deletions included.
+    pub changed: Vec<String>,
+    /// Every path the commit would track.
+    pub files: Vec<String>,
+}
+
+/// A commit worked out but not yet written, see [`Sgvcs::prepare_commit`].
+struct PreparedCommit {
+    message: String,
+    files: Vec<IndexData>,
+    parents: Vec<String>,
+    changed: Vec<String>,
+    /// Staged entries left out of a partial commit, to stay in the index.
+    unstaged: Vec<IndexData>,
+    merging: bool,
+}
+
+/// Where a bisect stands after [`Sgvcs::bisect_start`], [`Sgvcs::bisect_good`]
+/// or [`Sgvcs::bisect_bad`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BisectStep {
+    /// `commit` is checked out to be tested; `remaining` commits could still
+    /// be the first bad one.
+    Testing { commit: String, remaining: usize },
+    /// Only this commit is left: it is the first bad one.
+    Found(String),
+}
+
+/// Bisect progress kept in `.sgvcs/BISECT`.
+#[derive(Serialize, Deserialize, Debug)]
+struct BisectState {
+    /// The branch HEAD was on when the bisect started, or the commit if it
+    /// was detached, to return to afterwards.
+    original: String,
+    bad: String,
+    good: Vec<String>,
+}
+
+/// Outcome of [`Sgvcs::merge`]. `commit` is what HEAD points at afterwards, or
+/// `None` when conflicts stopped the merge before committing.
+#[derive(Debug, Default)]
+pub struct MergeResult {
+    pub commit: Option<String>,
+    pub conflicts: Vec<String>,
+}
+
+/// One line of `.sgvcs/logs/HEAD`: HEAD moved from `old` to `new`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    pub old: String,
+    pub new: String,
+    pub timestamp: String,
+    pub operation: String,
+    /// Branch HEAD pointed to once the move was made, `None` when HEAD was
+    /// detached or the entry predates branch hints. Kept out of commits so it
+    /// never changes a commit hash.
+    pub branch: Option<String>,
+}
+
+/// Held while the index is being changed; dropping it removes the lock file,
+/// so the lock is released on every return path, errors included.
+struct IndexLock {
+    path: PathBuf,
+}
+
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
     }
+}
 
-    pub async fn update_staging_area(&mut self, file_path: &Path, file_hash: String) {
-        let mut index_file = fs::File::open(&self.index_path).await.unwrap();
-        let mut buffer = String::new();
-        index_file.read_to_string(&mut buffer).await.unwrap();
-        let mut data: Vec<IndexData> = serde_json::from_str(&buffer).unwrap();
-        let index_data = IndexData {
-            path: file_path.to_str().unwrap().to_string(),
-            hash: file_hash.to_string(),
-        };
-        data.push(index_data);
-        let data_json = serde_json::to_string_pretty(&data).unwrap();
-        let mut index_file = fs::File::create(&self.index_path).await.unwrap();
-        index_file.write_all(data_json.as_bytes()).await.unwrap();
+/// One shelved set of changes in `.sgvcs/stash`, from [`Sgvcs::stash_push`].
+/// Files are referenced by blob hash, so their contents live in the object
+/// store like everything else.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StashEntry {
+    /// The commit HEAD pointed at when the changes were stashed.
+    pub base: String,
+    pub message: String,
+    /// The index as it was, staged deletions included.
+    index: Vec<IndexData>,
+    /// Tracked files whose working-tree contents differed from what was
+    /// staged; a missing file is recorded as a deletion.
+    worktree: Vec<IndexData>,
+}
+
+impl StashEntry {
+    /// Every path the entry touches, sorted.
+    fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self
+            .index
+            .iter()
+            .chain(&self.worktree)
+            .map(|file| file.path.clone())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
     }
+}
 
-    pub async fn commit(&mut self, message: String) {
-        let mut index_file: fs::File = fs::File::open(&self.index_path).await.unwrap();
-        let mut buffer: String = String::new();
-        index_file.read_to_string(&mut buffer).await.unwrap();
-        let parent_commit: String = self.get_current_head().await;
+/// One line of a file with the commit that last changed it, from
+/// [`Sgvcs::blame`].
+#[derive(Debug, Clone)]
+pub struct BlameLine {
+    pub commit: String,
+    pub author: String,
+    pub text: String,
+}
 
-        let commit = CommitData {
-            message,
-            time_stamp: Utc::now().format("%d-%m-%Y %H:%M:%S").to_string(),
-            files: serde_json::from_str(&buffer).unwrap(),
-            parent: parent_commit,
-        };
+/// Reported once per file by [`Sgvcs::add_dir_with_progress`] and
+/// [`Sgvcs::clone_with_progress`]. `current` counts from 1 up to `total`.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub current: usize,
+    pub total: usize,
+    pub path: PathBuf,
+}
+
+/// What a repo records about itself, read by [`Sgvcs::repo_info`].
+#[derive(Debug)]
+pub struct RepoInfo {
+    pub description: String,
+    pub format_version: u32,
+    /// `None` for repos created before the creation time was recorded.
+    pub created: Option<DateTime<Utc>>,
+    pub hash_algo: HashAlgo,
+}
 
-        let commit_json = serde_json::to_string_pretty(&commit).unwrap();
-        let commit_hash = Self::hash(commit_json.as_bytes());
-        let commit_path = self.objects_path.join(commit_hash.clone());
-        let mut commit_file = fs::File::create(&commit_path).await.unwrap();
-        commit_file.write_all(commit_json.as_bytes()).await.unwrap();
+/// What [`Sgvcs::gc`] removed, or would remove on a dry run.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub objects: usize,
+    pub bytes: u64,
+    pub removed: Vec<String>,
+}
 
-        let mut head_file = fs::File::create(&self.head_path).await.unwrap();
-        head_file.write_all(commit_hash.as_bytes()).await.unwrap();
+/// Object store statistics from [`Sgvcs::count_objects`]. `bytes` is the
+/// compressed size on disk.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ObjectStats {
+    pub objects: usize,
+    pub bytes: u64,
+    pub commits: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    pub unreachable: usize,
+}
 
-        let mut index_file = fs::File::create(&self.index_path).await.unwrap();
-        index_file.write_all(b"[]").await.unwrap();
+/// How much [`Sgvcs::reset`] rewinds besides HEAD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Only move HEAD (or the current branch).
+    Soft,
+    /// Move HEAD and clear the index.
+    Mixed,
+    /// Move HEAD, clear the index and restore the working tree.
+    Hard,
+}
 
-        println!("Committed: {:?}", commit_hash);
-    }
+/// A problem found by [`Sgvcs::fsck`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckError {
+    /// The object stored as `hash` actually hashes to `actual`.
+    HashMismatch { hash: String, actual: String },
+    /// `referenced_by` (a commit or tree) names an object that is not stored.
+    MissingObject { hash: String, referenced_by: String },
+    /// A commit's parent commit is not in the store.
+    DanglingParent { commit: String, parent: String },
+    /// Rebuilding the tree from the files a commit records gives `actual`
+    /// rather than the commit's `tree`, so the stored trees are not in the
+    /// canonical sorted form.
+    TreeMismatch {
+        commit: String,
+        tree: String,
+        actual: String,
+    },
+}
 
-    async fn get_current_head(&self) -> String {
-        match fs::File::open(&self.head_path).await {
-            Ok(mut head_file) => {
-                let mut buffer = String::new();
-                match head_file.read_to_string(&mut buffer).await {
-                    Ok(_) => buffer,
-                    Err(_) => String::new(), // Return empty string on read error
-                }
+impl fmt::Display for FsckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsckError::HashMismatch { hash, actual } => {
+                write!(f, "hash mismatch: {} contains {}", hash, actual)
+            }
+            FsckError::MissingObject {
+                hash,
+                referenced_by,
+            } => write!(
+                f,
+                "missing object {} (referenced by {})",
+                hash, referenced_by
+            ),
+            FsckError::DanglingParent { commit, parent } => {
+                write!(f, "dangling parent {} of commit {}", parent, commit)
             }
-            Err(_) => String::new(), // Return empty string if file cannot be opened
+            FsckError::TreeMismatch {
+                commit,
+                tree,
+                actual,
+            } => write!(
+                f,
+                "tree mismatch: commit {} records tree {} but its files hash to {}",
+                commit, tree, actual
+            ),
         }
     }
+}
 
-    pub async fn log(&mut self) {
-        let mut current_hash: String = self.get_current_head().await;
-        while !current_hash.is_empty() {
-            let mut commit_file = fs::File::open(self.objects_path.join(current_hash.clone()))
-                .await
-                .unwrap();
-            let mut buffer = String::new();
-            commit_file.read_to_string(&mut buffer).await.unwrap();
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub staged: Vec<String>,
+    pub modified: Vec<String>,
+    /// Tracked files missing from the working tree whose deletion is not
+    /// staged.
+    pub deleted: Vec<String>,
+    pub untracked: Vec<String>,
+}
+
+/// Full human-readable view of a commit: author, time, parents, tree and every
+/// tracked file, then the message indented like `show` does.
+impl fmt::Display for CommitData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "author: {} <{}>", self.author, self.email)?;
+        writeln!(f, "time_stamp: {}", self.time_stamp)?;
+        let parents: Vec<String> = self.parent_hashes();
+        if parents.is_empty() {
+            writeln!(f, "parent: (root)")?;
+        } else {
+            let short: Vec<&str> = parents.iter().map(|hash| Sgvcs::abbrev(hash)).collect();
+            writeln!(f, "parent: {}", short.join(" "))?;
+        }
+        if !self.tree.is_empty() {
+            writeln!(f, "tree: {}", Sgvcs::abbrev(&self.tree))?;
+        }
+        writeln!(f, "files: {}", self.files.len())?;
+        for file in &self.files {
+            writeln!(f, "    {} {}", Sgvcs::abbrev(&file.hash), file.path)?;
+        }
+        writeln!(f)?;
+        for line in self.message.lines() {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
 
-            let commit: CommitData = serde_json::from_str(&buffer).unwrap();
+impl CommitData {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The message up to its first blank line.
+    pub fn subject(&self) -> &str {
+        split_message(&self.message).0
+    }
+
+    /// The message after its first blank line, empty when there is none.
+    pub fn body(&self) -> &str {
+        split_message(&self.message).1
+    }
+
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    /// The display timestamp, `%d-%m-%Y %H:%M:%S` in UTC.
+    pub fn time_stamp(&self) -> &str {
+        &self.time_stamp
+    }
+
+    /// First parent, empty for a root commit.
+    pub fn parent(&self) -> &str {
+        &self.parent
+    }
+
+    /// Tracked files as `(path, blob hash)` pairs.
+    pub fn files(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.files
+            .iter()
+            .map(|file| (file.path.as_str(), file.hash.as_str()))
+    }
 
-            println!("\nCommit: {}", current_hash);
-            println!("{:?}", commit);
+    /// Commit time from `date`, falling back to parsing `time_stamp` for
+    /// older commits.
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.date)
+            .or_else(|_| DateTime::parse_from_rfc3339(&self.time_stamp))
+            .map(|time| time.with_timezone(&Utc))
+            .ok()
+            .or_else(|| {
+                NaiveDateTime::parse_from_str(&self.time_stamp, DISPLAY_TIME_FORMAT)
+                    .ok()
+                    .map(|time| time.and_utc())
+            })
+    }
+
+    /// The canonical encoding a commit is stored and hashed as: compact JSON
+    /// with keys in sorted order, so the hash depends only on the commit's
+    /// fields and not on how serde lays out or indents the struct. The file
+    /// list is not part of it; it lives in the tree, whose entries are
+    /// already sorted by name.
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>> {
+        canonical_json(self)
+    }
 
-            current_hash = commit.parent.clone();
+    /// Every parent, first parent first; empty for a root commit.
+    pub fn parent_hashes(&self) -> Vec<String> {
+        if !self.parents.is_empty() {
+            self.parents.clone()
+        } else if !self.parent.is_empty() {
+            vec![self.parent.clone()]
+        } else {
+            Vec::new()
         }
     }
+}
 
-    pub async fn show_commit_diff(&self, commithash: String) {
-        let commit_data: Option<CommitData> = self.get_commit_data(commithash).await;
-        match commit_data {
-            Some(commit) => {
-                println!("Changes in the last commit are: ");
-                for file in commit.files {
-                    println!("File: {}", file.path.to_string());
-                    let file_content: String = self.get_file_contents(file.hash).await;
-                    println!("{:?}", file_content);
-                    if !commit.parent.is_empty() {
-                        let parent_data: Option<CommitData> =
-                            self.get_commit_data(commit.parent.clone()).await;
-                        match parent_data {
-                            Some(data) => {
-                                let file_parent_contents = self
-                                    .get_parent_file_content(
-                                        data,
-                                        &self.objects_path.join(file.path.clone()),
-                                    )
-                                    .await;
-                                println!("{:?}", file_parent_contents);
-                            }
-                            None => println!(
-                                "Parent commit not found for this file: {}",
-                                file.path.clone()
-                            ),
-                        }
-                    } else {
-                        println!("First commit");
-                    }
-                }
-            },
-            None => println!("Commit not found"),
+/// Compact JSON with every object's keys in sorted order, the encoding commits
+/// and trees are stored and hashed as.
+fn canonical_json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    // serde_json's map is ordered by key, so a round trip through `Value`
+    // sorts every object's fields
+    let value: serde_json::Value = serde_json::to_value(value)?;
+    Ok(serde_json::to_vec(&value)?)
+}
+
+/// How [`Sgvcs::build_tree`] turns a tree's entries into object content.
+type TreeEncoding = fn(&[TreeEntry]) -> Result<Vec<u8>>;
+
+/// A tree object's hash and content, ready to be written.
+type TreeObject = (String, Vec<u8>);
+
+/// Trees written before the canonical encoding, kept so fsck can still
+/// reproduce their hashes.
+fn pretty_tree(entries: &[TreeEntry]) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(entries)?)
+}
+
+/// Splits a commit message at its first blank line into subject and body,
+/// trimming the blank lines around both.
+fn split_message(message: &str) -> (&str, &str) {
+    let message: &str = message.trim();
+    let mut offset: usize = 0;
+    for line in message.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            return (message[..offset].trim_end(), message[offset..].trim());
         }
+        offset += line.len();
     }
+    (message, "")
+}
+
+/// Lazy walk from a commit back to the root along first parents, created by
+/// [`Sgvcs::history`]. Each call to `next` loads one commit.
+pub struct History<'a> {
+    repo: &'a Sgvcs,
+    next: String,
+    resolved: bool,
+    visited: HashSet<String>,
+}
 
-    async fn get_commit_data(&self, commithash: String) -> Option<CommitData> {
-        let commit_file = fs::File::open(self.objects_path.join(commithash.clone())).await;
-        match commit_file {
-            Ok(mut commit_data) => {
-                let mut buffer = String::new();
-                commit_data.read_to_string(&mut buffer).await.unwrap();
-                let data: CommitData = serde_json::from_str(&buffer).unwrap();
-                Some(data)
+impl History<'_> {
+    /// The next commit and its hash, newest first. Returns `None` after the
+    /// root commit, and ends the walk after yielding an error, including for
+    /// a parent chain that loops back on itself.
+    pub async fn next(&mut self) -> Option<Result<(String, CommitData)>> {
+        if self.next.is_empty() {
+            return None;
+        }
+        let revision: String = std::mem::take(&mut self.next);
+        let hash: String = if self.resolved {
+            revision
+        } else {
+            self.resolved = true;
+            match self.repo.resolve_revision(&revision).await {
+                Ok(hash) => hash,
+                Err(err) => return Some(Err(err)),
             }
-            Err(e) => {
-                println!("Commit not found {}", e);
-                None
+        };
+        if !self.visited.insert(hash.clone()) {
+            return Some(Err(SgvcsError::CorruptObject {
+                hash,
+                reason: "history is cyclic, the commit is its own ancestor".to_string(),
+            }));
+        }
+        match self.repo.get_commit_data(hash.clone()).await {
+            Ok(commit) => {
+                self.next = commit.parent.clone();
+                Some(Ok((hash, commit)))
             }
+            Err(err) => Some(Err(err)),
         }
     }
+}
 
-    fn hash(content: &[u8]) -> String {
-        let mut hasher: sha1::digest::core_api::CoreWrapper<sha1::Sha1Core> = Sha1::new();
-        hasher.update(content);
-        let result = hasher.finalize();
+/// Incremental hasher for the repo's [`HashAlgo`].
+enum StreamHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl StreamHasher {
+    fn update(&mut self, chunk: &[u8]) {
+        match self {
+            StreamHasher::Sha1(hasher) => hasher.update(chunk),
+            StreamHasher::Sha256(hasher) => hasher.update(chunk),
+        }
+    }
+
+    fn finish(self) -> String {
+        let result: Vec<u8> = match self {
+            StreamHasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            StreamHasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        };
         let mut hash_hex = String::new();
         for byte in result.iter() {
             hash_hex.push_str(&format!("{:02x}", byte));
         }
         hash_hex
     }
+}
 
-    async fn get_file_contents(&self, file_hash: String) -> String {
-        let mut file: fs::File = fs::File::open(self.objects_path.join(file_hash))
-            .await
-            .unwrap();
-        let mut content: String = String::new();
-        file.read_to_string(&mut content).await.unwrap();
-        content
+impl Sgvcs {
+    pub async fn new_async() -> Result<Sgvcs> {
+        let mut sgvcs: Sgvcs = Sgvcs::new()?;
+        sgvcs.load_objects_dir().await?;
+        sgvcs.init().await?;
+        sgvcs.hash_algo = sgvcs.stored_hash_algo().await?;
+        Ok(sgvcs)
     }
 
-    async fn get_parent_file_content(
-        &self,
-        parent_commit_data: CommitData,
-        file_path: &Path,
-    ) -> Option<String> {
-        let file_hash = parent_commit_data
-            .files
-            .iter()
-            .find(|file| file.path == file_path.to_str().unwrap());
-        match file_hash {
-            Some(file) => {
-                let file_content = self.get_file_contents(file.hash.clone()).await;
-                Some(file_content)
+    /// Opens the repo containing the current directory, found the same way as
+    /// by [`Sgvcs::new`], without creating or migrating anything. Fails with
+    /// [`SgvcsError::NotARepo`] when there is none; creating one is left to
+    /// [`Sgvcs::init`].
+    pub async fn open() -> Result<Sgvcs> {
+        let curr_dir: PathBuf = std::env::current_dir()?;
+        let root_path: PathBuf = curr_dir
+            .ancestors()
+            .find(|dir| dir.join(".sgvcs").is_dir())
+            .ok_or_else(|| SgvcsError::NotARepo(curr_dir.clone()))?
+            .to_path_buf();
+        Sgvcs::at(root_path).load().await
+    }
+
+    /// Like [`Sgvcs::new_async`], but a new repo is created with `algo` as its
+    /// hash function. Opening an existing repo that uses a different algorithm
+    /// fails, since object names from two algorithms cannot be mixed.
+    pub async fn new_with_hash_algo(algo: HashAlgo) -> Result<Sgvcs> {
+        let mut sgvcs: Sgvcs = Sgvcs::new()?;
+        sgvcs.load_objects_dir().await?;
+        sgvcs.init().await?;
+        let configured: bool = sgvcs.get_config(HASH_ALGO_KEY).await?.is_some();
+        if !configured && sgvcs.iter_objects().next().transpose()?.is_none() {
+            sgvcs.write_config(HASH_ALGO_KEY, algo.name()).await?;
+        }
+        let stored: HashAlgo = sgvcs.stored_hash_algo().await?;
+        if stored != algo {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Repository already uses {}, cannot switch to {}",
+                stored.name(),
+                algo.name()
+            )));
+        }
+        sgvcs.hash_algo = stored;
+        Ok(sgvcs)
+    }
+
+    /// Opens the repo whose working tree is `root`, creating it if needed like
+    /// [`Sgvcs::new_async`], with its objects kept in `objects` rather than
+    /// in `.sgvcs/objects`. Refs, the index and the config are still files
+    /// under `root`. Handy with a [`MemoryObjectStore`] in tests.
+    ///
+    /// [`MemoryObjectStore`]: crate::store::MemoryObjectStore
+    pub async fn with_object_store(
+        root: &Path,
+        objects: impl ObjectStore + 'static,
+    ) -> Result<Sgvcs> {
+        fs::create_dir_all(root).await?;
+        let mut sgvcs: Sgvcs = Sgvcs::at(fs::canonicalize(root).await?);
+        sgvcs.objects = Box::new(objects);
+        sgvcs.init().await?;
+        sgvcs.hash_algo = sgvcs.stored_hash_algo().await?;
+        Ok(sgvcs)
+    }
+
+    /// Points the object store at `core.objectsdir` when the config sets it.
+    /// Called before [`Sgvcs::init`], which creates the directory if needed.
+    async fn load_objects_dir(&mut self) -> Result<()> {
+        if let Some(dir) = self.get_config(OBJECTS_DIR_KEY).await? {
+            self.objects = Box::new(FsObjectStore::new(Self::objects_dir_setting(&dir)?));
+        }
+        Ok(())
+    }
+
+    fn objects_dir_setting(value: &str) -> Result<PathBuf> {
+        let dir: PathBuf = PathBuf::from(value);
+        if !dir.is_absolute() {
+            return Err(SgvcsError::InvalidInput(format!(
+                "{} must be an absolute path, got {:?}",
+                OBJECTS_DIR_KEY, value
+            )));
+        }
+        Ok(dir)
+    }
+
+    async fn stored_hash_algo(&self) -> Result<HashAlgo> {
+        match self.get_config(HASH_ALGO_KEY).await? {
+            Some(name) => HashAlgo::parse(&name),
+            None => Ok(HashAlgo::Sha1),
+        }
+    }
+
+    /// Opens the repo containing the current directory: the nearest ancestor
+    /// with a `.sgvcs` directory, like git. Outside any repo the current
+    /// directory is used, so `init` creates a new repo there. Fails only when
+    /// the current directory cannot be read, for instance after it was deleted.
+    pub fn new() -> Result<Sgvcs> {
+        let curr_dir: PathBuf = std::env::current_dir()?;
+        let root_path: PathBuf = curr_dir
+            .ancestors()
+            .find(|dir| dir.join(".sgvcs").is_dir())
+            .unwrap_or(&curr_dir)
+            .to_path_buf();
+        Ok(Sgvcs::at(root_path))
+    }
+
+    /// Handle for the repo whose working tree is `root_path`. Nothing is read
+    /// or created until [`Sgvcs::init`].
+    fn at(root_path: PathBuf) -> Sgvcs {
+        let repo_path: PathBuf = root_path.join(".sgvcs");
+        Sgvcs::with_paths(root_path, repo_path, false)
+    }
+
+    fn with_paths(root_path: PathBuf, repo_path: PathBuf, bare: bool) -> Sgvcs {
+        let objects: Box<dyn ObjectStore> = Box::new(FsObjectStore::new(repo_path.join("objects")));
+        let index_path: PathBuf = repo_path.join("index");
+        let head_path: PathBuf = repo_path.join("HEAD");
+        let heads_path: PathBuf = repo_path.join("refs").join("heads");
+        let tags_path: PathBuf = repo_path.join("refs").join("tags");
+        let config_path: PathBuf = repo_path.join("config");
+        let reflog_path: PathBuf = repo_path.join("logs").join("HEAD");
+        let description_path: PathBuf = repo_path.join("description");
+        let stash_path: PathBuf = repo_path.join("stash");
+
+        Sgvcs {
+            root_path,
+            repo_path,
+            objects,
+            index_path,
+            head_path,
+            heads_path,
+            tags_path,
+            config_path,
+            reflog_path,
+            description_path,
+            stash_path,
+            bare,
+            hash_algo: HashAlgo::default(),
+            merge_bases: Mutex::new(HashMap::new()),
+            output: Output(Mutex::new(Box::new(std::io::stdout()))),
+            prepare_commit_msg: MessageHook(Mutex::new(None)),
+            temp_names: TempNames::new(),
+        }
+    }
+
+    /// Creates a bare repository in `path`: the repo contents live directly
+    /// in it, with no `.sgvcs` subfolder and no working tree. `path` may not
+    /// exist yet but otherwise must be an empty directory.
+    pub async fn init_bare(path: &Path) -> Result<Sgvcs> {
+        if path.exists() {
+            let mut entries = fs::read_dir(path).await?;
+            if entries.next_entry().await?.is_some() {
+                return Err(SgvcsError::AlreadyExists(format!(
+                    "Cannot create a bare repository in {:?}: directory is not empty",
+                    path
+                )));
             }
-            None => None,
         }
+        fs::create_dir_all(path).await?;
+        let root_path: PathBuf = fs::canonicalize(path).await?;
+        let mut sgvcs: Sgvcs = Sgvcs::with_paths(root_path.clone(), root_path, true);
+        sgvcs
+            .write_config(CREATED_KEY, &Self::now_rfc3339())
+            .await?;
+        sgvcs.write_config(BARE_KEY, "true").await?;
+        sgvcs.init().await?;
+        sgvcs.hash_algo = sgvcs.stored_hash_algo().await?;
+        Ok(sgvcs)
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.bare
+    }
+
+    fn require_work_tree(&self, command: &str) -> Result<()> {
+        if self.bare {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Cannot {} in a bare repository: it has no working tree",
+                command
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sends all further command output to `output` instead of stdout, for
+    /// embedding the crate or capturing output in tests.
+    pub fn set_output(&self, output: impl Write + Send + 'static) {
+        *self.output.0.lock().unwrap_or_else(PoisonError::into_inner) = Box::new(output);
+    }
+
+    /// Writes command output. Output is informational, so a sink that fails
+    /// does not fail the command.
+    fn emit(&self, args: fmt::Arguments) {
+        let mut output: MutexGuard<Box<dyn Write + Send>> =
+            self.output.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = output.write_fmt(args);
+    }
+
+    /// Copies the repository at `source` into `dest` and checks out its HEAD
+    /// commit there. The new repo starts with an empty index.
+    pub async fn clone(source: &Path, dest: &Path) -> Result<Sgvcs> {
+        Self::clone_with_progress(source, dest, &mut |_| {}).await
+    }
+
+    /// Like [`Sgvcs::clone`], calling `progress` after each file is written to
+    /// the new working tree.
+    pub async fn clone_with_progress(
+        source: &Path,
+        dest: &Path,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<Sgvcs> {
+        let source_repo: Sgvcs = Self::open_at(source).await?;
+        if dest.join(".sgvcs").exists() {
+            return Err(SgvcsError::AlreadyExists(format!(
+                "{:?} already contains a repository",
+                dest
+            )));
+        }
+        fs::create_dir_all(dest).await?;
+        let mut sgvcs: Sgvcs = Sgvcs::at(fs::canonicalize(dest).await?);
+        // Only what describes the history is copied; merge, bisect and stash
+        // state, the reflog and any lock belong to the source's working tree
+        fs::create_dir_all(&sgvcs.repo_path).await?;
+        for name in CLONED_REPO_FILES {
+            let from: PathBuf = source_repo.repo_path.join(name);
+            let to: PathBuf = sgvcs.repo_path.join(name);
+            if from.is_dir() {
+                Self::copy_dir(&from, &to).await?;
+            } else if from.is_file() {
+                fs::copy(&from, &to).await?;
+            }
+        }
+        if let Some(dir) = source_repo.objects.dir() {
+            // The clone gets its own store even when the source's lives
+            // outside its repo directory
+            Self::copy_dir(dir, &sgvcs.repo_path.join("objects")).await?;
+        }
+        if sgvcs.get_config(OBJECTS_DIR_KEY).await?.is_some() {
+            sgvcs.unset_config(OBJECTS_DIR_KEY).await?;
+        }
+        sgvcs.write_index(&[]).await?;
+        sgvcs
+            .write_config(CREATED_KEY, &Self::now_rfc3339())
+            .await?;
+        if sgvcs.get_config(BARE_KEY).await?.is_some() {
+            sgvcs.write_config(BARE_KEY, "false").await?;
+        }
+        sgvcs.init().await?;
+        sgvcs.hash_algo = sgvcs.stored_hash_algo().await?;
+
+        let head: String = sgvcs.get_current_head().await?;
+        let files: Vec<IndexData> = sgvcs.commit_files(&head).await?;
+        let total: usize = files.len();
+        for (i, file) in files.into_iter().enumerate() {
+            sgvcs
+                .write_working_file(&file.path, &sgvcs.read_object(&file.hash).await?, file.mode)
+                .await?;
+            progress(Progress {
+                current: i + 1,
+                total,
+                path: PathBuf::from(file.path),
+            });
+        }
+        say!(sgvcs, "Cloned {:?} into {:?}", source, dest);
+        Ok(sgvcs)
+    }
+
+    /// The directory holding the repo at `path` and whether it is bare: a
+    /// bare repo holds the repo contents directly.
+    fn find_repo_dir(path: &Path) -> Result<(PathBuf, bool)> {
+        if path.join(".sgvcs").is_dir() {
+            Ok((path.join(".sgvcs"), false))
+        } else if path.join("HEAD").is_file() && path.join("config").is_file() {
+            Ok((path.to_path_buf(), true))
+        } else {
+            Err(SgvcsError::NotARepo(path.to_path_buf()))
+        }
+    }
+
+    /// Handle for the existing repo at `path`, without creating or migrating
+    /// anything in it.
+    async fn open_at(path: &Path) -> Result<Sgvcs> {
+        let (repo_path, bare) = Self::find_repo_dir(path)?;
+        let root_path: PathBuf = fs::canonicalize(path).await?;
+        let repo_path: PathBuf = fs::canonicalize(repo_path).await?;
+        Sgvcs::with_paths(root_path, repo_path, bare).load().await
+    }
+
+    /// Reads the settings an existing repo needs before use, refusing repos
+    /// in a newer format.
+    async fn load(mut self) -> Result<Sgvcs> {
+        if let Some(found) = self.stored_format_version().await? {
+            if found > FORMAT_VERSION {
+                return Err(SgvcsError::IncompatibleRepo {
+                    found,
+                    supported: FORMAT_VERSION,
+                });
+            }
+        }
+        self.load_objects_dir().await?;
+        self.hash_algo = self.stored_hash_algo().await?;
+        Ok(self)
+    }
+
+    /// Handle for another repo on this machine, to read or update its refs
+    /// and objects. It must use the same hash algorithm as this one.
+    async fn open_remote(&self, path: &Path) -> Result<Sgvcs> {
+        let remote: Sgvcs = Self::open_at(path).await?;
+        if remote.hash_algo != self.hash_algo {
+            return Err(SgvcsError::InvalidInput(format!(
+                "{:?} uses {}, but this repository uses {}",
+                path,
+                remote.hash_algo.name(),
+                self.hash_algo.name()
+            )));
+        }
+        Ok(remote)
+    }
+
+    /// Tip of `branch`, or an empty string when the branch does not exist.
+    async fn branch_tip(&self, branch: &str) -> Result<String> {
+        Self::validate_ref_name(branch)?;
+        match fs::read_to_string(self.heads_path.join(branch)).await {
+            Ok(hash) => Ok(hash.trim().to_string()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(String::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Name of the branch HEAD follows, or `None` when HEAD is detached.
+    async fn current_branch(&self) -> Option<String> {
+        let head: String = fs::read_to_string(&self.head_path).await.ok()?;
+        head.trim()
+            .strip_prefix(HEAD_REF_PREFIX)
+            .map(str::to_string)
+    }
+
+    /// Copies every object reachable from `tip` in `from` that `to` lacks.
+    /// Objects are copied as stored, so their names stay valid. Returns how
+    /// many were copied.
+    async fn transfer_objects(from: &Sgvcs, to: &Sgvcs, tip: &str) -> Result<usize> {
+        let mut reachable: HashSet<String> = HashSet::new();
+        from.collect_reachable(tip, &mut reachable).await?;
+        let mut copied: usize = 0;
+        for hash in reachable {
+            if to.object_exists(&hash) {
+                continue;
+            }
+            let stored: Vec<u8> = from.read_object_file(&hash).await?;
+            to.write_object_file(&hash, &stored).await?;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    /// Sends `branch` to the repo at `remote`: copies the objects the remote
+    /// lacks, then moves the remote branch to the local tip. Only
+    /// fast-forwards are allowed, and the branch checked out in a remote
+    /// with a working tree is left alone, since moving it would leave that
+    /// working tree out of date. Returns how many objects were copied.
+    pub async fn push(&self, remote: &Path, branch: &str) -> Result<usize> {
+        let local_tip: String = self.branch_tip(branch).await?;
+        if local_tip.is_empty() {
+            return Err(SgvcsError::NotFound(format!(
+                "Branch {:?} does not exist",
+                branch
+            )));
+        }
+        let remote_repo: Sgvcs = self.open_remote(remote).await?;
+        let remote_tip: String = remote_repo.branch_tip(branch).await?;
+        if !remote_tip.is_empty() && !self.reaches(&local_tip, &remote_tip).await? {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Rejected push to {:?}: the remote branch has commits the local branch does not",
+                branch
+            )));
+        }
+        if !remote_repo.bare && remote_repo.current_branch().await.as_deref() == Some(branch) {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Rejected push to {:?}: it is checked out in {:?}",
+                branch, remote
+            )));
+        }
+        let copied: usize = Self::transfer_objects(self, &remote_repo, &local_tip).await?;
+        // Objects first, so the remote ref never names a missing commit
+        remote_repo
+            .write_atomic(&remote_repo.heads_path.join(branch), local_tip.as_bytes())
+            .await?;
+        say!(
+            self,
+            "Pushed {} to {:?} ({} objects)",
+            branch,
+            remote,
+            copied
+        );
+        Ok(copied)
+    }
+
+    /// Fetches `branch` from the repo at `remote` and fast-forwards the local
+    /// branch to it, creating the branch if needed. With `checkout` set the
+    /// branch is then checked out; a working tree with local changes makes
+    /// the pull fail before anything is changed. Returns how many objects
+    /// were copied.
+    pub async fn pull(&mut self, remote: &Path, branch: &str, checkout: bool) -> Result<usize> {
+        let remote_repo: Sgvcs = self.open_remote(remote).await?;
+        let remote_tip: String = remote_repo.branch_tip(branch).await?;
+        if remote_tip.is_empty() {
+            return Err(SgvcsError::NotFound(format!(
+                "Branch {:?} does not exist in {:?}",
+                branch, remote
+            )));
+        }
+        if checkout {
+            self.require_work_tree("checkout")?;
+            if !self.is_clean().await? {
+                return Err(SgvcsError::InvalidInput(
+                    "Local changes would be overwritten by pull: commit them first".to_string(),
+                ));
+            }
+        }
+        let copied: usize = Self::transfer_objects(&remote_repo, self, &remote_tip).await?;
+        let local_tip: String = self.branch_tip(branch).await?;
+        if !local_tip.is_empty() && !self.reaches(&remote_tip, &local_tip).await? {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Rejected pull of {:?}: the local branch has commits the remote branch does not; merge instead",
+                branch
+            )));
+        }
+
+        let on_branch: bool = self.current_branch().await.as_deref() == Some(branch);
+        if on_branch && checkout {
+            self.reset(&remote_tip, ResetMode::Hard).await?;
+        } else if on_branch {
+            self.update_head(&remote_tip, &format!("pull: fast-forward {}", branch))
+                .await?;
+        } else {
+            self.write_atomic(&self.heads_path.join(branch), remote_tip.as_bytes())
+                .await?;
+            if checkout {
+                self.checkout(branch, false).await?;
+            }
+        }
+        say!(
+            self,
+            "Pulled {} from {:?} ({} objects)",
+            branch,
+            remote,
+            copied
+        );
+        Ok(copied)
+    }
+
+    async fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+        let mut pending: Vec<(PathBuf, PathBuf)> = vec![(from.to_path_buf(), to.to_path_buf())];
+        while let Some((from, to)) = pending.pop() {
+            fs::create_dir_all(&to).await?;
+            let mut entries = fs::read_dir(&from).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                // Temporary files of an interrupted write are not worth copying
+                if entry.file_name().to_string_lossy().starts_with("tmp_") {
+                    continue;
+                }
+                let target: PathBuf = to.join(entry.file_name());
+                if entry.file_type().await?.is_dir() {
+                    pending.push((entry.path(), target));
+                } else {
+                    fs::copy(entry.path(), target).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates whatever parts of the repo are missing and brings an existing
+    /// repo up to [`FORMAT_VERSION`]: loose objects are sharded, a raw-hash
+    /// HEAD becomes a branch, and the version is recorded. Objects written
+    /// before headers existed keep their names and are still read as they
+    /// are. A repo written by a newer build is refused before anything on
+    /// disk is touched. Existing files such as HEAD and the index are never
+    /// overwritten, so calling it on an existing repo is safe; the outcome
+    /// tells the two cases apart.
+    pub async fn init(&self) -> Result<InitOutcome> {
+        if let Some(found) = self.stored_format_version().await? {
+            if found > FORMAT_VERSION {
+                return Err(SgvcsError::IncompatibleRepo {
+                    found,
+                    supported: FORMAT_VERSION,
+                });
+            }
+        }
+        // A repo without HEAD is not usable yet, so finishing one counts as
+        // creating it
+        let outcome: InitOutcome = if self.head_path.exists() {
+            InitOutcome::AlreadyExists
+        } else {
+            InitOutcome::Created
+        };
+        if !self.repo_path.exists() {
+            fs::create_dir_all(&self.repo_path).await?;
+            self.write_config(CREATED_KEY, &Self::now_rfc3339()).await?;
+            say!(self, "Created repo directory: {:?}", self.repo_path);
+        } else {
+            say!(self, "Repo directory already exists: {:?}", self.repo_path);
+        }
+
+        // Create the objects directory if it does not exist
+        if let Some(dir) = self.objects.dir() {
+            if !dir.exists() {
+                fs::create_dir_all(dir).await?;
+                say!(self, "Created objects directory: {:?}", dir);
+            } else {
+                say!(self, "Objects directory already exists: {:?}", dir);
+                self.migrate_objects().await?;
+            }
+        }
+
+        // Create the index file and write an empty array if it does not exist
+        if !self.index_path.exists() {
+            fs::write(&self.index_path, b"[]").await?;
+            say!(
+                self,
+                "Created index file with empty array: {:?}",
+                self.index_path
+            );
+        } else {
+            say!(self, "Index file already exists: {:?}", self.index_path);
+        }
+
+        // Create the branch refs directory if it does not exist
+        if !self.heads_path.exists() {
+            fs::create_dir_all(&self.heads_path).await?;
+            say!(self, "Created refs directory: {:?}", self.heads_path);
+        }
+        if !self.tags_path.exists() {
+            fs::create_dir_all(&self.tags_path).await?;
+            say!(self, "Created tags directory: {:?}", self.tags_path);
+        }
+
+        // Create HEAD as a symbolic reference to the default branch
+        if !self.head_path.exists() {
+            self.set_head(DEFAULT_BRANCH).await?;
+            say!(self, "Created HEAD file: {:?}", self.head_path);
+        } else {
+            say!(self, "HEAD file already exists: {:?}", self.head_path);
+            self.migrate_head().await?;
+        }
+
+        if !self.description_path.exists() {
+            fs::write(&self.description_path, DEFAULT_DESCRIPTION).await?;
+            say!(
+                self,
+                "Created description file: {:?}",
+                self.description_path
+            );
+        }
+        // Older repos have the current layout once the migrations above have
+        // run, so only the recorded version is left to update
+        if self.stored_format_version().await? != Some(FORMAT_VERSION) {
+            self.write_config(FORMAT_VERSION_KEY, &FORMAT_VERSION.to_string())
+                .await?;
+        }
+
+        Ok(outcome)
+    }
+
+    /// Reads back the description, format version, creation time and hash
+    /// algorithm recorded for this repo.
+    pub async fn repo_info(&self) -> Result<RepoInfo> {
+        let description: String = match fs::read_to_string(&self.description_path).await {
+            Ok(description) => description.trim_end().to_string(),
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let format_version: u32 = self
+            .stored_format_version()
+            .await?
+            .unwrap_or(FORMAT_VERSION);
+        let created: Option<DateTime<Utc>> = self
+            .get_config(CREATED_KEY)
+            .await?
+            .and_then(|created| DateTime::parse_from_rfc3339(&created).ok())
+            .map(|created| created.with_timezone(&Utc));
+        Ok(RepoInfo {
+            description,
+            format_version,
+            created,
+            hash_algo: self.stored_hash_algo().await?,
+        })
+    }
+
+    /// The recorded `core.formatversion`, or `None` for repos from before it
+    /// was recorded.
+    async fn stored_format_version(&self) -> Result<Option<u32>> {
+        match self.get_config(FORMAT_VERSION_KEY).await? {
+            Some(version) => match version.parse() {
+                Ok(version) => Ok(Some(version)),
+                Err(_) => Err(SgvcsError::InvalidInput(format!(
+                    "Invalid {} {:?}",
+                    FORMAT_VERSION_KEY, version
+                ))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn now_rfc3339() -> String {
+        Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+
+    /// Older repos stored the current commit hash directly in HEAD and had no
+    /// branches. Move the hash onto the default branch and make HEAD point at
+    /// that branch instead. A raw hash alongside existing branches is a
+    /// detached HEAD and is left as is.
+    async fn migrate_head(&self) -> Result<()> {
+        let head: String = fs::read_to_string(&self.head_path).await?;
+        let head: &str = head.trim();
+        if head.starts_with("ref: ") || !self.list_branches().await?.is_empty() {
+            return Ok(());
+        }
+        if !head.is_empty() {
+            self.write_atomic(&self.heads_path.join(DEFAULT_BRANCH), head.as_bytes())
+                .await?;
+        }
+        self.set_head(DEFAULT_BRANCH).await?;
+        say!(self, "Migrated HEAD to branch {:?}", DEFAULT_BRANCH);
+        Ok(())
+    }
+
+    pub async fn add_file(&mut self, path: &Path) -> Result<()> {
+        self.require_work_tree("add")?;
+        if !path.is_file() {
+            return Err(SgvcsError::NotFound(format!(
+                "Cannot add {:?}: no such file",
+                path
+            )));
+        }
+        let autocrlf: AutoCrlf = self.autocrlf().await?;
+        let hashed_data: String = if autocrlf == AutoCrlf::False {
+            let file: fs::File = fs::File::open(path).await?;
+            let len: u64 = file.metadata().await?.len();
+            self.write_object_from(file, len).await?
+        } else {
+            // Conversion needs the whole file, so it cannot be streamed
+            let content: Vec<u8> = fs::read(path).await?;
+            self.hash_object(&autocrlf.clean(&content), true).await?
+        };
+        self.update_staging_area(path, hashed_data.clone()).await?;
+        say!(self, "Added {:?} to index", path);
+        Ok(())
+    }
+
+    pub async fn add_dir(&mut self, dir: &Path) -> Result<usize> {
+        self.add_dir_with_progress(dir, &mut |_| {}).await
+    }
+
+    /// Like [`Sgvcs::add_dir`], calling `progress` after each file is staged.
+    pub async fn add_dir_with_progress(
+        &mut self,
+        dir: &Path,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<usize> {
+        self.require_work_tree("add")?;
+        let ignore: IgnoreSet = self.load_ignore().await?;
+        let files: Vec<PathBuf> = self.walk_files(dir, &ignore).await?;
+        for (i, path) in files.iter().enumerate() {
+            self.add_file(&self.root_path.join(path)).await?;
+            progress(Progress {
+                current: i + 1,
+                total: files.len(),
+                path: path.clone(),
+            });
+        }
+
+        // Tracked files under `dir` that are gone from disk were deleted
+        let relative: PathBuf = self.relative_path(dir)?;
+        let prefix: String = if relative.as_os_str().is_empty() {
+            String::new()
+        } else {
+            format!("{}/", Self::path_key(&relative)?)
+        };
+        let mut deleted: usize = 0;
+        for file in self.staged_files().await? {
+            if file.path.starts_with(&prefix) && !self.root_path.join(&file.path).exists() {
+                self.stage_deletion_key(&file.path).await?;
+                say!(self, "Staged deletion of {:?}", file.path);
+                deleted += 1;
+            }
+        }
+        Ok(files.len() + deleted)
+    }
+
+    /// Reads `.sgvcsignore` from the repo root. A missing file ignores nothing.
+    pub async fn load_ignore(&self) -> Result<IgnoreSet> {
+        match fs::read_to_string(self.root_path.join(".sgvcsignore")).await {
+            Ok(contents) => {
+                let ignore: IgnoreSet = IgnoreSet::parse(&contents);
+                for warning in ignore.warnings() {
+                    say!(self, "{}", warning);
+                }
+                Ok(ignore)
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(IgnoreSet::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn walk_files(&self, dir: &Path, ignore: &IgnoreSet) -> Result<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        let mut pending: Vec<PathBuf> = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            let mut entries = fs::read_dir(&current).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type: std::fs::FileType = entry.file_type().await?;
+                let path: PathBuf = entry.path();
+                let relative: PathBuf = self.relative_path(&path)?;
+                // Symlinks are skipped rather than followed to avoid cycles
+                if file_type.is_symlink() {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    if entry.file_name() == ".sgvcs" || ignore.is_ignored(&relative, true) {
+                        continue;
+                    }
+                    pending.push(path);
+                } else if file_type.is_file() && !ignore.is_ignored(&relative, false) {
+                    files.push(relative);
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Turns a path given relative to the current directory (or absolute)
+    /// into the path relative to the repo root that the index stores. `.` and
+    /// `..` are resolved first, and a path that then lies outside the working
+    /// tree is refused.
+    fn relative_path(&self, path: &Path) -> Result<PathBuf> {
+        let absolute: PathBuf = if path.is_relative() {
+            std::env::current_dir()?.join(path)
+        } else {
+            path.to_path_buf()
+        };
+        let mut normalized: PathBuf = PathBuf::new();
+        for component in absolute.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                other => normalized.push(other),
+            }
+        }
+        match normalized.strip_prefix(&self.root_path) {
+            Ok(relative) => Ok(relative.to_path_buf()),
+            Err(_) => Err(SgvcsError::InvalidInput(format!(
+                "{:?} is outside the repository at {:?}",
+                path, self.root_path
+            ))),
+        }
+    }
+
+    /// Where index key `key` lives in the working tree. A key that is
+    /// absolute or climbs with `..` could point outside it and is refused.
+    /// Every working-tree write and delete goes through here, so a crafted
+    /// tree, index or stash entry cannot touch files outside the tree.
+    fn working_path(&self, key: &str) -> Result<PathBuf> {
+        let relative: &Path = Path::new(key);
+        let escapes: bool = relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+        if escapes {
+            return Err(SgvcsError::InvalidInput(format!(
+                "{:?} is not a path inside the working tree",
+                key
+            )));
+        }
+        Ok(self.root_path.join(relative))
+    }
+
+    fn path_key(path: &Path) -> Result<String> {
+        path.to_str()
+            .map(str::to_string)
+            .ok_or_else(|| SgvcsError::InvalidInput(format!("Path {:?} is not valid UTF-8", path)))
+    }
+
+    async fn read_index(&self) -> Result<Vec<IndexData>> {
+        let buffer: String = fs::read_to_string(&self.index_path).await?;
+        serde_json::from_str(&buffer).map_err(|err| SgvcsError::CorruptIndex(err.to_string()))
+    }
+
+    /// Checks that the index parses and that every blob it stages is in the
+    /// object store, failing with [`SgvcsError::CorruptIndex`] otherwise.
+    pub async fn verify_index(&self) -> Result<()> {
+        for entry in self.read_index().await? {
+            if !entry.is_deletion() && !self.object_exists(&entry.hash) {
+                return Err(SgvcsError::CorruptIndex(format!(
+                    "{:?} is staged as {}, which is not stored",
+                    entry.path, entry.hash
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes `.sgvcs/index.lock` for a read-modify-write of the index, failing
+    /// with [`SgvcsError::Locked`] while another process holds it. The lock
+    /// is released when the returned guard is dropped.
+    async fn lock_index(&self) -> Result<IndexLock> {
+        let path: PathBuf = self.repo_path.join(INDEX_LOCK_FILE);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(_) => Ok(IndexLock { path }),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => Err(SgvcsError::Locked(path)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn write_index(&self, data: &[IndexData]) -> Result<()> {
+        let data_json: String = serde_json::to_string_pretty(data)?;
+        self.write_atomic(&self.index_path, data_json.as_bytes())
+            .await
+    }
+
+    /// Replaces `path` by writing a temporary file in the repo directory and
+    /// renaming it over `path`, so an interrupted write leaves either the old
+    /// or the new contents and never a truncated file. Objects are written
+    /// by their [`ObjectStore`] instead.
+    async fn write_atomic(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let name: String = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let temp_path: PathBuf = self.repo_path.join(self.temp_names.next(&name));
+        let mut temp: fs::File = fs::File::create(&temp_path).await?;
+        temp.write_all(contents).await?;
+        temp.sync_all().await?;
+        drop(temp);
+        if let Err(err) = fs::rename(&temp_path, path).await {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    pub async fn update_staging_area(&mut self, file_path: &Path, file_hash: String) -> Result<()> {
+        let _lock: IndexLock = self.lock_index().await?;
+        let mut data: Vec<IndexData> = self.read_index().await?;
+        let path: String = Self::path_key(&self.relative_path(file_path)?)?;
+        let mode: u32 = match fs::metadata(file_path).await {
+            Ok(metadata) => Self::file_mode(&metadata),
+            Err(_) => DEFAULT_MODE,
+        };
+        // Re-adding a path replaces its staged hash instead of duplicating the entry
+        match data.iter_mut().find(|entry| entry.path == path) {
+            Some(entry) => {
+                entry.hash = file_hash;
+                entry.mode = mode;
+            }
+            None => data.push(IndexData {
+                path,
+                hash: file_hash,
+                mode,
+            }),
+        }
+        self.write_index(&data).await
+    }
+
+    /// Stops tracking `path`: a committed file has its deletion staged, a
+    /// file that is only staged is dropped from the index. Unless
+    /// `keep_working` is set the file is deleted from the working tree as
+    /// well; otherwise it stays on disk as an untracked file.
+    pub async fn rm(&mut self, path: &Path, keep_working: bool) -> Result<()> {
+        self.require_work_tree("rm")?;
+        let key: String = Self::path_key(&self.relative_path(path)?)?;
+        self.stage_deletion_key(&key).await?;
+        if !keep_working {
+            let working_path: PathBuf = self.working_path(&key)?;
+            if working_path.exists() {
+                fs::remove_file(&working_path).await?;
+            }
+        }
+        say!(self, "Removed {:?} from index", key);
+        Ok(())
+    }
+
+    /// Stages the deletion of a tracked file, typically one already removed
+    /// from the working tree, so the next commit no longer records it. A
+    /// file that is only staged, not committed, is simply unstaged. The
+    /// working tree is not touched.
+    pub async fn stage_deletion(&mut self, path: &Path) -> Result<()> {
+        self.require_work_tree("stage a deletion")?;
+        let key: String = Self::path_key(&self.relative_path(path)?)?;
+        self.stage_deletion_key(&key).await?;
+        say!(self, "Staged deletion of {:?}", key);
+        Ok(())
+    }
+
+    async fn stage_deletion_key(&self, key: &str) -> Result<()> {
+        let _lock: IndexLock = self.lock_index().await?;
+        let committed: bool = self.head_files().await?.iter().any(|file| file.path == key);
+        let mut data: Vec<IndexData> = self.read_index().await?;
+        let staged: bool = data.iter().any(|entry| entry.path == key);
+        if !committed && !staged {
+            return Err(SgvcsError::NotFound(format!("{:?} is not tracked", key)));
+        }
+        data.retain(|entry| entry.path != key);
+        if committed {
+            data.push(IndexData::deletion(key.to_string()));
+        }
+        self.write_index(&data).await
+    }
+
+    /// Drops `path` from the index, or clears the whole index when `path` is
+    /// `None`. The working tree is never touched.
+    pub async fn unstage(&mut self, path: Option<&Path>) -> Result<()> {
+        let _lock: IndexLock = self.lock_index().await?;
+        match path {
+            Some(path) => {
+                let key: String = self.remove_from_index(path).await?;
+                say!(self, "Unstaged {:?}", key);
+            }
+            None => {
+                self.write_index(&[]).await?;
+                say!(self, "Cleared the index");
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_from_index(&self, path: &Path) -> Result<String> {
+        let mut data: Vec<IndexData> = self.read_index().await?;
+        let key: String = Self::path_key(&self.relative_path(path)?)?;
+        let before: usize = data.len();
+        data.retain(|entry| entry.path != key);
+        if data.len() == before {
+            return Err(SgvcsError::NotFound(format!(
+                "{:?} is not in the index",
+                key
+            )));
+        }
+        self.write_index(&data).await?;
+        Ok(key)
+    }
+
+    /// Commits the staged changes and returns the new commit hash. Fails when
+    /// nothing differs from the parent commit unless `allow_empty` is set.
+    pub async fn commit(&mut self, message: String, allow_empty: bool) -> Result<String> {
+        self.commit_with_time(message, Utc::now(), allow_empty)
+            .await
+    }
+
+    /// Like [`Sgvcs::commit`] but stamps the commit with `time` instead of the
+    /// current time, so the same content always gives the same commit hash.
+    /// Returns the new commit hash.
+    pub async fn commit_with_time(
+        &mut self,
+        message: String,
+        time: DateTime<Utc>,
+        allow_empty: bool,
+    ) -> Result<String> {
+        self.commit_staged(message, time, allow_empty, None).await
+    }
+
+    /// Commits only the staged entries for `paths`, leaving everything else
+    /// staged for a later commit. Every path must be staged. Returns the new
+    /// commit hash.
+    pub async fn commit_paths(&mut self, message: String, paths: &[PathBuf]) -> Result<String> {
+        let keys: Vec<String> = paths
+            .iter()
+            .map(|path| Self::path_key(&self.relative_path(path)?))
+            .collect::<Result<Vec<String>>>()?;
+        self.commit_staged(message, Utc::now(), false, Some(&keys))
+            .await
+    }
+
+    /// Works out what committing now would record, like [`Sgvcs::commit`],
+    /// without writing objects, moving HEAD or touching the index. Fails
+    /// the same way the commit would, for instance with nothing staged.
+    pub async fn commit_dry_run(
+        &self,
+        message: String,
+        allow_empty: bool,
+    ) -> Result<CommitPreview> {
+        let prepared: PreparedCommit = self.prepare_commit(message, allow_empty, None).await?;
+        for path in &prepared.changed {
+            say!(self, "Would commit {}", path);
+        }
+        Ok(CommitPreview {
+            message: prepared.message,
+            parents: prepared.parents,
+            changed: prepared.changed,
+            files: prepared.files.into_iter().map(|file| file.path).collect(),
+        })
+    }
+
+    /// Commits the staged entries, or only those whose path is in `only`, and
+    /// writes whatever was not committed back to the index.
+    async fn commit_staged(
+        &mut self,
+        message: String,
+        time: DateTime<Utc>,
+        allow_empty: bool,
+        only: Option<&[String]>,
+    ) -> Result<String> {
+        let _lock: IndexLock = self.lock_index().await?;
+        let prepared: PreparedCommit = self.prepare_commit(message, allow_empty, only).await?;
+        let operation: String = if prepared.merging {
+            format!("commit (merge): {}", Self::subject(&prepared.message))
+        } else {
+            format!("commit: {}", Self::subject(&prepared.message))
+        };
+        // Each step leaves a usable repo if the next one never runs: the
+        // commit object is written before HEAD points at it, and the index
+        // is only cleared after, so entries left behind match HEAD and read
+        // as nothing staged
+        let commit_hash: String = self
+            .write_commit(prepared.message, prepared.files, &prepared.parents, time)
+            .await?;
+        self.update_head(&commit_hash, &operation).await?;
+
+        self.write_index(&prepared.unstaged).await?;
+        if prepared.merging {
+            self.clear_merge_state().await?;
+        }
+
+        say!(self, "Committed: {:?}", commit_hash);
+        self.warn_if_detached(&commit_hash).await;
+        Ok(commit_hash)
+    }
+
+    /// Everything [`Sgvcs::commit_staged`] decides before it writes: the
+    /// checks, the files and parents of the new commit and its final message.
+    async fn prepare_commit(
+        &self,
+        message: String,
+        allow_empty: bool,
+        only: Option<&[String]>,
+    ) -> Result<PreparedCommit> {
+        self.author_identity().await?;
+        let staged: Vec<IndexData> = self.dedupe_entries(self.read_index().await?);
+        let (staged, unstaged): (Vec<IndexData>, Vec<IndexData>) = match only {
+            Some(paths) => {
+                if let Some(path) = paths
+                    .iter()
+                    .find(|path| !staged.iter().any(|entry| entry.path == **path))
+                {
+                    return Err(SgvcsError::NotFound(format!("{:?} is not staged", path)));
+                }
+                staged
+                    .into_iter()
+                    .partition(|entry| paths.contains(&entry.path))
+            }
+            None => (staged, Vec::new()),
+        };
+        let parent_commit: String = self.get_current_head().await?;
+        let merging: Option<(String, Vec<String>)> = self.read_merge_state().await?;
+        if let Some((_, conflicts)) = &merging {
+            if only.is_some() {
+                return Err(SgvcsError::InvalidInput(
+                    "Cannot commit only some paths during a merge".to_string(),
+                ));
+            }
+            let head_files: Vec<IndexData> = self.commit_files(&parent_commit).await?;
+            if let Some(path) = conflicts.iter().find(|path| {
+                // Resolved once staged, or once removed when HEAD never had it
+                !staged.iter().any(|entry| entry.path == **path)
+                    && (head_files.iter().any(|file| file.path == **path)
+                        || self.root_path.join(path).exists())
+            }) {
+                return Err(SgvcsError::InvalidInput(format!(
+                    "Cannot commit: {:?} still has conflicts; stage it once resolved",
+                    path
+                )));
+            }
+        }
+
+        // A commit records the full tracked tree: the parent's files with the
+        // staged entries laid over them
+        let mut files: Vec<IndexData> = self.commit_files(&parent_commit).await?;
+        let mut changed: Vec<String> = staged
+            .iter()
+            .filter(|entry| {
+                !files.iter().any(|file| {
+                    file.path == entry.path && file.hash == entry.hash && file.mode == entry.mode
+                })
+            })
+            .map(|entry| entry.path.clone())
+            .collect();
+        changed.sort();
+        // A merge commit is worth recording even when it keeps our tree
+        if changed.is_empty() && !allow_empty && merging.is_none() {
+            return Err(SgvcsError::NothingToCommit);
+        }
+        Self::overlay_staged(&mut files, staged);
+
+        let mut parents: Vec<String> = if parent_commit.is_empty() {
+            Vec::new()
+        } else {
+            vec![parent_commit]
+        };
+        if let Some((merge_head, _)) = &merging {
+            parents.push(merge_head.clone());
+        }
+        Ok(PreparedCommit {
+            message: self.prepare_message(message).await?,
+            files,
+            parents,
+            changed,
+            unstaged,
+            merging: merging.is_some(),
+        })
+    }
+
+    /// Replaces HEAD's commit with a new one on the same parents, taking
+    /// `new_message` if given and folding in anything staged. The old commit
+    /// is left dangling. Returns the new commit hash.
+    pub async fn amend(&mut self, new_message: Option<String>) -> Result<String> {
+        self.amend_with_time(new_message, Utc::now()).await
+    }
+
+    /// Like [`Sgvcs::amend`] but stamps the new commit with `time`; pass the
+    /// old commit's time to keep it. Staged paths override the old commit's
+    /// files, and only a staged deletion takes a file out, so a file missing
+    /// from the working tree is kept. Refused while a merge is in progress.
+    pub async fn amend_with_time(
+        &mut self,
+        new_message: Option<String>,
+        time: DateTime<Utc>,
+    ) -> Result<String> {
+        let _lock: IndexLock = self.lock_index().await?;
+        let head: String = self.get_current_head().await?;
+        if head.is_empty() {
+            return Err(SgvcsError::NoCommits);
+        }
+        if self.read_merge_state().await?.is_some() {
+            return Err(SgvcsError::InvalidInput(
+                "Cannot amend during a merge: commit or abort it first".to_string(),
+            ));
+        }
+        let previous: CommitData = self.load_commit(&head).await?;
+        let parents: Vec<String> = previous.parent_hashes();
+        let staged: Vec<IndexData> = self.dedupe_entries(self.read_index().await?);
+        let mut files: Vec<IndexData> = previous.files;
+        Self::overlay_staged(&mut files, staged);
+        let message: String = self
+            .prepare_message(new_message.unwrap_or(previous.message))
+            .await?;
+        let operation: String = format!("commit (amend): {}", Self::subject(&message));
+
+        let commit_hash: String = self.write_commit(message, files, &parents, time).await?;
+        self.update_head(&commit_hash, &operation).await?;
+        self.write_index(&[]).await?;
+        say!(
+            self,
+            "Amended {} as {:?}",
+            self.short_hash(&head).await?,
+            commit_hash
+        );
+        self.warn_if_detached(&commit_hash).await;
+        Ok(commit_hash)
+    }
+
+    /// Lays staged entries over a commit's files; a staged path replaces the
+    /// committed one and a staged deletion removes it.
+    fn overlay_staged(files: &mut Vec<IndexData>, staged: Vec<IndexData>) {
+        for entry in staged {
+            if entry.is_deletion() {
+                files.retain(|file| file.path != entry.path);
+                continue;
+            }
+            match files.iter_mut().find(|file| file.path == entry.path) {
+                Some(file) => {
+                    file.hash = entry.hash;
+                    file.mode = entry.mode;
+                }
+                None => files.push(entry),
+            }
+        }
+    }
+
+    /// Collapses entries sharing a path, keeping the last one. `add` never
+    /// writes duplicates, but hand-edited or older indexes may contain them.
+    fn dedupe_entries(&self, mut entries: Vec<IndexData>) -> Vec<IndexData> {
+        let before: usize = entries.len();
+        let mut seen: HashSet<String> = HashSet::new();
+        entries.reverse();
+        entries.retain(|entry| seen.insert(entry.path.clone()));
+        entries.reverse();
+        if entries.len() < before {
+            say!(
+                self,
+                "Warning: the index listed {} duplicate path(s), keeping the last entry for each",
+                before - entries.len()
+            );
+        }
+        entries
+    }
+
+    async fn author_identity(&self) -> Result<(String, String)> {
+        match (
+            self.get_config("user.name").await?,
+            self.get_config("user.email").await?,
+        ) {
+            (Some(author), Some(email)) => Ok((author, email)),
+            _ => Err(SgvcsError::InvalidInput(
+                "Author identity unknown: set user.name and user.email with set_config".to_string(),
+            )),
+        }
+    }
+
+    /// Writes a commit object for `files` and returns its hash; callers move
+    /// HEAD. Only merge commits record the full `parents` list.
+    async fn write_commit(
+        &self,
+        message: String,
+        files: Vec<IndexData>,
+        parents: &[String],
+        time: DateTime<Utc>,
+    ) -> Result<String> {
+        let (author, email) = self.author_identity().await?;
+        let tree: String = self.write_tree(&files).await?;
+        let commit = CommitData {
+            message,
+            time_stamp: time.format(DISPLAY_TIME_FORMAT).to_string(),
+            date: time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            files,
+            parent: parents.first().cloned().unwrap_or_default(),
+            author,
+            email,
+            tree,
+            parents: if parents.len() > 1 {
+                parents.to_vec()
+            } else {
+                Vec::new()
+            },
+        };
+
+        let commit_bytes: Vec<u8> = commit.canonical_bytes()?;
+        let commit_hash: String = self.hash(ObjectType::Commit, &commit_bytes);
+        self.write_object(&commit_hash, ObjectType::Commit, &commit_bytes)
+            .await?;
+        Ok(commit_hash)
+    }
+
+    /// Registers `hook` to rewrite every message passed to commit or amend
+    /// before it is recorded, like a `prepare-commit-msg` hook. It replaces
+    /// any hook registered before.
+    pub fn set_prepare_commit_msg(&self, hook: impl Fn(&str) -> String + Send + 'static) {
+        *self
+            .prepare_commit_msg
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+    }
+
+    /// The contents of `.sgvcs/commit-template`, if the repo has one.
+    pub async fn commit_template(&self) -> Result<Option<String>> {
+        match fs::read_to_string(self.repo_path.join(COMMIT_TEMPLATE_FILE)).await {
+            Ok(template) => Ok(Some(template)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Runs the registered message hook over `message`. When the result is
+    /// blank and the repo has a commit template, the commit is refused and
+    /// the template handed back to be filled in; without a template blank
+    /// messages are recorded as before.
+    async fn prepare_message(&self, message: String) -> Result<String> {
+        let message: String = match &*self
+            .prepare_commit_msg
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            Some(hook) => hook(&message),
+            None => message,
+        };
+        if message.trim().is_empty() {
+            if let Some(template) = self.commit_template().await? {
+                return Err(SgvcsError::EmptyMessage { template });
+            }
+        }
+        Ok(message)
+    }
+
+    /// Whether HEAD holds a commit hash instead of naming a branch, as after
+    /// checking out a commit. Commits then move only HEAD.
+    pub async fn is_detached(&self) -> bool {
+        self.current_branch().await.is_none()
+    }
+
+    /// Warns that `commit_hash`, just made on a detached HEAD, is reachable
+    /// from no branch and will be lost when HEAD moves unless one is created.
+    async fn warn_if_detached(&self, commit_hash: &str) {
+        if self.is_detached().await {
+            // The commit is already made, so a failed lookup only costs the
+            // abbreviation
+            let short: String = self
+                .short_hash(commit_hash)
+                .await
+                .unwrap_or_else(|_| commit_hash.to_string());
+            say!(
+                self,
+                "Warning: HEAD is detached, so {} is on no branch; create one to keep it",
+                short
+            );
+        }
+    }
+
+    /// First line of a commit message's subject, used to label reflog
+    /// entries and `log --oneline`.
+    fn subject(message: &str) -> &str {
+        split_message(message).0.lines().next().unwrap_or_default()
+    }
+
+    /// Restores the files recorded in `target` (a branch name or commit hash)
+    /// into the working tree. Checking out a branch makes HEAD follow it;
+    /// checking out a raw commit detaches HEAD. Files tracked by the current
+    /// HEAD but absent from the target are only deleted when `force` is set;
+    /// untracked files are never touched. Without `force`, staged changes or
+    /// edits to tracked files make the checkout fail instead of being lost.
+    pub async fn checkout(&mut self, target: &str, force: bool) -> Result<()> {
+        self.require_work_tree("checkout")?;
+        let branch_path: PathBuf = self.heads_path.join(target);
+        let is_branch: bool = Self::validate_ref_name(target).is_ok() && branch_path.is_file();
+        if !is_branch && self.current_branch().await.as_deref() == Some(target) {
+            // The current branch before its first commit
+            return Err(SgvcsError::NoCommits);
+        }
+        let commit_hash: String = if is_branch {
+            fs::read_to_string(&branch_path).await?.trim().to_string()
+        } else {
+            self.resolve_revision(target).await?
+        };
+        let commit: CommitData = self.load_commit(&commit_hash).await?;
+        if !force && !self.is_clean().await? {
+            return Err(SgvcsError::InvalidInput(
+                "Local changes would be overwritten by checkout: commit them or force the checkout"
+                    .to_string(),
+            ));
+        }
+
+        let current_head: String = self.get_current_head().await?;
+        let current_files: Vec<IndexData> = self.commit_files(&current_head).await?;
+        for file in &commit.files {
+            self.write_working_file(&file.path, &self.read_object(&file.hash).await?, file.mode)
+                .await?;
+        }
+        if force {
+            for file in current_files {
+                let path: PathBuf = self.working_path(&file.path)?;
+                if !commit.files.iter().any(|kept| kept.path == file.path) && path.exists() {
+                    fs::remove_file(&path).await?;
+                }
+            }
+        }
+
+        if is_branch {
+            self.set_head(target).await?;
+            say!(self, "Switched to branch {:?}", target);
+        } else {
+            self.write_atomic(&self.head_path, commit_hash.as_bytes())
+                .await?;
+            say!(self, "HEAD is now detached at {}", commit_hash);
+        }
+        self.record_reflog(
+            &current_head,
+            &commit_hash,
+            &format!("checkout: moving to {}", target),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Checks out, detached, the newest commit on HEAD's first-parent history
+    /// made at or before `when`, and returns its hash. Commits whose time
+    /// cannot be parsed are skipped; local changes block the checkout as
+    /// with [`Sgvcs::checkout`].
+    pub async fn checkout_as_of(&mut self, when: DateTime<Utc>) -> Result<String> {
+        if self.get_current_head().await?.is_empty() {
+            return Err(SgvcsError::NoCommits);
+        }
+        let mut chosen: Option<(DateTime<Utc>, String)> = None;
+        let mut history: History = self.history("HEAD");
+        while let Some(next) = history.next().await {
+            let (hash, commit) = next?;
+            // Clocks can be skewed, so keep looking instead of stopping at the
+            // first match
+            match commit.time() {
+                Some(time)
+                    if time <= when && chosen.as_ref().is_none_or(|(best, _)| time > *best) =>
+                {
+                    chosen = Some((time, hash))
+                }
+                _ => {}
+            }
+        }
+        let Some((_, hash)) = chosen else {
+            return Err(SgvcsError::NotFound(format!(
+                "No commit at or before {}",
+                when.to_rfc3339_opts(SecondsFormat::Secs, true)
+            )));
+        };
+        self.checkout(&hash, false).await?;
+        Ok(hash)
+    }
+
+    /// Moves HEAD (the current branch when HEAD is symbolic) to `commit`.
+    /// `Mixed` also clears the index, and `Hard` additionally rewrites the
+    /// working tree to the commit's files, deleting tracked or staged files
+    /// the commit does not have. Any merge in progress is forgotten.
+    pub async fn reset(&mut self, commit: &str, mode: ResetMode) -> Result<()> {
+        if mode == ResetMode::Hard {
+            self.require_work_tree("reset --hard")?;
+        }
+        let _lock: IndexLock = self.lock_index().await?;
+        let commit_hash: String = self.resolve_revision(commit).await?;
+        let target: CommitData = self.load_commit(&commit_hash).await?;
+        if mode == ResetMode::Hard {
+            let mut tracked: Vec<IndexData> =
+                self.commit_files(&self.get_current_head().await?).await?;
+            tracked.extend(self.read_index().await?);
+            for file in &target.files {
+                self.write_working_file(
+                    &file.path,
+                    &self.read_object(&file.hash).await?,
+                    file.mode,
+                )
+                .await?;
+            }
+            for file in tracked {
+                let path: PathBuf = self.working_path(&file.path)?;
+                if !target.files.iter().any(|kept| kept.path == file.path) && path.exists() {
+                    fs::remove_file(&path).await?;
+                }
+            }
+        }
+        if mode != ResetMode::Soft {
+            self.write_index(&[]).await?;
+        }
+        self.clear_merge_state().await?;
+        self.update_head(&commit_hash, &format!("reset: moving to {}", commit))
+            .await?;
+        say!(
+            self,
+            "HEAD is now at {}",
+            self.short_hash(&commit_hash).await?
+        );
+        Ok(())
+    }
+
+    /// Shelves the index and the working-tree changes to tracked files as a
+    /// new entry on top of the stash stack, then puts the working tree and
+    /// index back to HEAD. Untracked files are left alone. Fails when there
+    /// is nothing to stash.
+    pub async fn stash_push(&mut self) -> Result<()> {
+        self.require_work_tree("stash")?;
+        let _lock: IndexLock = self.lock_index().await?;
+        let head: String = self.get_current_head().await?;
+        if head.is_empty() {
+            return Err(SgvcsError::NoCommits);
+        }
+        let index: Vec<IndexData> = self.dedupe_entries(self.read_index().await?);
+        let autocrlf: AutoCrlf = self.autocrlf().await?;
+        let mut worktree: Vec<IndexData> = Vec::new();
+        for file in self.staged_files().await? {
+            let path: PathBuf = self.working_path(&file.path)?;
+            match fs::read(&path).await {
+                Ok(content) if self.blob_matches(&file.hash, &autocrlf.clean(&content)) => {}
+                Ok(content) => worktree.push(IndexData {
+                    hash: self.hash_object(&autocrlf.clean(&content), true).await?,
+                    mode: Self::file_mode(&fs::metadata(&path).await?),
+                    path: file.path,
+                }),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    worktree.push(IndexData::deletion(file.path))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if index.is_empty() && worktree.is_empty() {
+            return Err(SgvcsError::InvalidInput(
+                "No local changes to stash".to_string(),
+            ));
+        }
+
+        let commit: CommitData = self.load_commit(&head).await?;
+        let branch: String = self
+            .current_branch()
+            .await
+            .unwrap_or_else(|| "(no branch)".to_string());
+        let entry: StashEntry = StashEntry {
+            base: head.clone(),
+            message: format!(
+                "WIP on {}: {} {}",
+                branch,
+                self.short_hash(&head).await?,
+                commit.subject()
+            ),
+            index,
+            worktree,
+        };
+        // The changes are only dropped from the working tree once the stash
+        // holding them is safely written
+        let paths: Vec<String> = entry.paths();
+        let message: String = entry.message.clone();
+        let mut stack: Vec<StashEntry> = self.stash_list().await?;
+        stack.insert(0, entry);
+        self.write_stash(&stack).await?;
+
+        for path in paths {
+            match commit.files.iter().find(|file| file.path == path) {
+                Some(file) => {
+                    self.write_working_file(
+                        &file.path,
+                        &self.read_object(&file.hash).await?,
+                        file.mode,
+                    )
+                    .await?
+                }
+                None => {
+                    let working_path: PathBuf = self.working_path(&path)?;
+                    if working_path.exists() {
+                        fs::remove_file(&working_path).await?;
+                    }
+                }
+            }
+        }
+        self.write_index(&[]).await?;
+        say!(self, "Saved working directory and index state {}", message);
+        Ok(())
+    }
+
+    /// Reapplies the newest stash entry and drops it from the stack. The
+    /// stashed index is restored and the stashed files replace the ones in
+    /// the working tree, without merging, so the tree must be clean first.
+    /// HEAD may have moved since the changes were stashed.
+    pub async fn stash_pop(&mut self) -> Result<()> {
+        self.require_work_tree("stash")?;
+        let _lock: IndexLock = self.lock_index().await?;
+        let mut stack: Vec<StashEntry> = self.stash_list().await?;
+        if stack.is_empty() {
+            return Err(SgvcsError::NotFound("No stash entries".to_string()));
+        }
+        if !self.is_clean().await? {
+            return Err(SgvcsError::InvalidInput(
+                "Cannot pop the stash with local changes: commit or stash them first".to_string(),
+            ));
+        }
+        let entry: StashEntry = stack.remove(0);
+        let head_files: Vec<IndexData> = self.head_files().await?;
+        let tracked = |path: &str| head_files.iter().any(|file| file.path == path);
+        let paths: Vec<String> = entry.paths();
+        if let Some(path) = paths
+            .iter()
+            .find(|path| !tracked(path) && self.root_path.join(path).exists())
+        {
+            return Err(SgvcsError::AlreadyExists(format!(
+                "Cannot pop the stash: untracked file {:?} would be overwritten",
+                path
+            )));
+        }
+        for path in paths {
+            let wanted: &IndexData = entry
+                .worktree
+                .iter()
+                .chain(&entry.index)
+                .find(|file| file.path == path)
+                .expect("paths come from the entry");
+            let working_path: PathBuf = self.working_path(&path)?;
+            if wanted.is_deletion() {
+                if working_path.exists() {
+                    fs::remove_file(&working_path).await?;
+                }
+            } else {
+                self.write_working_file(&path, &self.read_object(&wanted.hash).await?, wanted.mode)
+                    .await?;
+            }
+        }
+        // A staged deletion of a file the new HEAD no longer has means nothing
+        let index: Vec<IndexData> = entry
+            .index
+            .into_iter()
+            .filter(|file| !file.is_deletion() || tracked(&file.path))
+            .collect();
+        self.write_index(&index).await?;
+        self.write_stash(&stack).await?;
+        say!(self, "Applied and dropped {}", entry.message);
+        Ok(())
+    }
+
+    /// The stash stack, newest entry first.
+    pub async fn stash_list(&self) -> Result<Vec<StashEntry>> {
+        match fs::read_to_string(&self.stash_path).await {
+            Ok(buffer) => Ok(serde_json::from_str(&buffer)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn write_stash(&self, stack: &[StashEntry]) -> Result<()> {
+        self.write_atomic(
+            &self.stash_path,
+            serde_json::to_string_pretty(stack)?.as_bytes(),
+        )
+        .await
+    }
+
+    /// Drops all but the newest `keep` stash entries and returns how many
+    /// were dropped. Their objects stay until [`Sgvcs::gc`] removes them.
+    pub async fn expire_stash(&self, keep: usize) -> Result<usize> {
+        let mut stack: Vec<StashEntry> = self.stash_list().await?;
+        if stack.len() <= keep {
+            return Ok(0);
+        }
+        let dropped: usize = stack.len() - keep;
+        stack.truncate(keep);
+        self.write_stash(&stack).await?;
+        say!(self, "Dropped {} old stash entries", dropped);
+        Ok(dropped)
+    }
+
+    /// Writes the version of one file recorded in `commit` into the working
+    /// tree. HEAD, the index and every other file are left alone.
+    pub async fn restore_file(&self, path: &Path, commit: &str) -> Result<()> {
+        self.require_work_tree("restore")?;
+        let key: String = Self::path_key(&self.relative_path(path)?)?;
+        let commit_hash: String = self.resolve_revision(commit).await?;
+        let commit_data: CommitData = self.load_commit(&commit_hash).await?;
+        let file: &IndexData = match commit_data.files.iter().find(|file| file.path == key) {
+            Some(file) => file,
+            None => {
+                return Err(SgvcsError::NotFound(format!(
+                    "{:?} does not exist in commit {}",
+                    key, commit_hash
+                )))
+            }
+        };
+        self.write_working_file(&file.path, &self.read_object(&file.hash).await?, file.mode)
+            .await?;
+        say!(
+            self,
+            "Restored {:?} from {}",
+            key,
+            self.short_hash(&commit_hash).await?
+        );
+        Ok(())
+    }
+
+    /// Paths recorded in `commit`, sorted. An empty `commit` means HEAD, and
+    /// HEAD with no commits yet lists nothing.
+    pub async fn list_files(&self, commit: &str) -> Result<Vec<PathBuf>> {
+        let commit: &str = if commit.is_empty() { "HEAD" } else { commit };
+        if commit == "HEAD" && self.get_current_head().await?.is_empty() {
+            return Ok(Vec::new());
+        }
+        let commit_data: CommitData = self.load_commit(commit).await?;
+        let mut paths: Vec<PathBuf> = commit_data
+            .files
+            .into_iter()
+            .map(|file| PathBuf::from(file.path))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Writes every file recorded in `commit` into a tar archive at `out`,
+    /// with paths relative to the repo root and the recorded file modes. The
+    /// working tree, index and HEAD are left alone.
+    pub async fn archive(&self, commit: &str, out: &Path) -> Result<()> {
+        let commit_hash: String = self.resolve_revision(commit).await?;
+        let commit_data: CommitData = self.load_commit(&commit_hash).await?;
+        let mtime: u64 = commit_data
+            .time()
+            .map(|time| time.timestamp().max(0) as u64)
+            .unwrap_or_default();
+        let mut files: Vec<&IndexData> = commit_data.files.iter().collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut builder: tar::Builder<Vec<u8>> = tar::Builder::new(Vec::new());
+        for file in files {
+            let content: Vec<u8> = self.read_object(&file.hash).await?;
+            let mut header: tar::Header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(file.mode);
+            header.set_mtime(mtime);
+            builder.append_data(&mut header, &file.path, content.as_slice())?;
+        }
+        fs::write(out, builder.into_inner()?).await?;
+        say!(
+            self,
+            "Archived {} into {:?}",
+            self.short_hash(&commit_hash).await?,
+            out
+        );
+        Ok(())
+    }
+
+    async fn write_working_file(&self, path: &str, content: &[u8], mode: u32) -> Result<()> {
+        let path: PathBuf = self.working_path(path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(&path, self.autocrlf().await?.smudge(content)).await?;
+        Self::apply_mode(&path, mode).await
+    }
+
+    #[cfg(unix)]
+    fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 != 0 {
+            EXECUTABLE_MODE
+        } else {
+            DEFAULT_MODE
+        }
+    }
+
+    /// Permission bits are not tracked outside Unix; every file gets the
+    /// default mode.
+    #[cfg(not(unix))]
+    fn file_mode(_metadata: &std::fs::Metadata) -> u32 {
+        DEFAULT_MODE
+    }
+
+    #[cfg(unix)]
+    async fn apply_mode(path: &Path, mode: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    async fn apply_mode(_path: &Path, _mode: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Merges `other_branch` (any revision) into the current branch. When HEAD
+    /// is an ancestor of the other side the branch is fast-forwarded; otherwise
+    /// each file is merged three ways against the common ancestor. A clean
+    /// merge is committed with both parents. On conflicts nothing is committed:
+    /// conflicted files are written with conflict markers, cleanly merged files
+    /// are staged, and the conflicted paths are returned. The merge then stays
+    /// in progress until [`Sgvcs::commit`] records it with both parents, once
+    /// every conflicted path is staged, or [`Sgvcs::merge_abort`] backs out.
+    /// Like checkout, a merge refuses to start with uncommitted changes.
+    pub async fn merge(&mut self, other_branch: &str) -> Result<MergeResult> {
+        self.require_work_tree("merge")?;
+        let _lock: IndexLock = self.lock_index().await?;
+        if self.read_merge_state().await?.is_some() {
+            return Err(SgvcsError::InvalidInput(
+                "A merge is already in progress: commit it or abort it first".to_string(),
+            ));
+        }
+        if !self.read_index().await?.is_empty() {
+            return Err(SgvcsError::InvalidInput(
+                "Cannot merge with staged changes: commit or unstage them first".to_string(),
+            ));
+        }
+        let head: String = self.get_current_head().await?;
+        if head.is_empty() {
+            return Err(SgvcsError::NoCommits);
+        }
+        if !self.is_clean().await? {
+            return Err(SgvcsError::InvalidInput(
+                "Local changes would be overwritten by merge: commit them first".to_string(),
+            ));
+        }
+        let other: String = self.resolve_revision(other_branch).await?;
+        let base: Option<String> = self.merge_base(&head, &other).await?;
+        if base.as_deref() == Some(other.as_str()) {
+            say!(self, "Already up to date");
+            return Ok(MergeResult {
+                commit: Some(head),
+                conflicts: Vec::new(),
+            });
+        }
+
+        let ours: Vec<IndexData> = self.commit_files(&head).await?;
+        let theirs: Vec<IndexData> = self.commit_files(&other).await?;
+        if base.as_deref() == Some(head.as_str()) {
+            self.update_working_tree(&ours, &theirs).await?;
+            self.update_head(&other, &format!("merge {}: fast-forward", other_branch))
+                .await?;
+            say!(self, "Fast-forward to {}", other);
+            return Ok(MergeResult {
+                commit: Some(other),
+                conflicts: Vec::new(),
+            });
+        }
+
+        let base_files: Vec<IndexData> = match &base {
+            Some(base) => self.commit_files(base).await?,
+            None => Vec::new(),
+        };
+        let find = |files: &[IndexData], path: &str| -> Option<String> {
+            files
+                .iter()
+                .find(|file| file.path == path)
+                .map(|file| file.hash.clone())
+        };
+        let mut paths: Vec<String> = ours
+            .iter()
+            .chain(theirs.iter())
+            .chain(base_files.iter())
+            .map(|file| file.path.clone())
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        let mut merged: Vec<IndexData> = Vec::new();
+        let mut conflicts: Vec<String> = Vec::new();
+        for path in paths {
+            let base_hash: Option<String> = find(&base_files, &path);
+            let our_hash: Option<String> = find(&ours, &path);
+            let their_hash: Option<String> = find(&theirs, &path);
+            let hash: Option<String> = if our_hash == their_hash || their_hash == base_hash {
+                our_hash
+            } else if our_hash == base_hash {
+                their_hash
+            } else if let (Some(our_hash), Some(their_hash)) = (&our_hash, &their_hash) {
+                let base_content: Vec<u8> = match &base_hash {
+                    Some(hash) => self.read_object(hash).await?,
+                    None => Vec::new(),
+                };
+                let our_content: Vec<u8> = self.read_object(our_hash).await?;
+                let their_content: Vec<u8> = self.read_object(their_hash).await?;
+                match (
+                    diff::as_text(&base_content),
+                    diff::as_text(&our_content),
+                    diff::as_text(&their_content),
+                ) {
+                    (Some(base_text), Some(our_text), Some(their_text)) => {
+                        let (text, conflicted) =
+                            diff::merge3(&base_text, &our_text, &their_text, "HEAD", other_branch);
+                        if conflicted {
+                            conflicts.push(path.clone());
+                        }
+                        let hash: String = self.hash(ObjectType::Blob, text.as_bytes());
+                        if !self.object_exists(&hash) {
+                            self.write_object(&hash, ObjectType::Blob, text.as_bytes())
+                                .await?;
+                        }
+                        Some(hash)
+                    }
+                    // Binary files cannot be merged line by line, keep ours
+                    _ => {
+                        conflicts.push(path.clone());
+                        Some(our_hash.clone())
+                    }
+                }
+            } else {
+                // Modified on one side and deleted on the other: keep the
+                // modified version in the working tree
+                conflicts.push(path.clone());
+                our_hash.or(their_hash)
+            };
+            if let Some(hash) = hash {
+                // Keep the mode of whichever side the content came from
+                let mode: u32 = theirs
+                    .iter()
+                    .chain(ours.iter())
+                    .filter(|file| file.path == path)
+                    .find(|file| file.hash == hash)
+                    .or_else(|| ours.iter().find(|file| file.path == path))
+                    .map_or(DEFAULT_MODE, |file| file.mode);
+                merged.push(IndexData { path, hash, mode });
+            }
+        }
+
+        self.update_working_tree(&ours, &merged).await?;
+        if conflicts.is_empty() {
+            let message: String = format!("Merge branch '{}'", other_branch);
+            let commit_hash: String = self
+                .write_commit(message, merged, &[head, other], Utc::now())
+                .await?;
+            self.update_head(&commit_hash, &format!("merge {}", other_branch))
+                .await?;
+            say!(self, "Merged {:?}: {}", other_branch, commit_hash);
+            return Ok(MergeResult {
+                commit: Some(commit_hash),
+                conflicts,
+            });
+        }
+
+        let mut staged: Vec<IndexData> = ours
+            .iter()
+            .filter(|our| !merged.iter().any(|file| file.path == our.path))
+            .map(|our| IndexData::deletion(our.path.clone()))
+            .collect();
+        staged.extend(merged.into_iter().filter(|file| {
+            !conflicts.contains(&file.path)
+                && !ours
+                    .iter()
+                    .any(|our| our.path == file.path && our.hash == file.hash)
+        }));
+        self.write_index(&staged).await?;
+        self.write_atomic(&self.repo_path.join(MERGE_HEAD_FILE), other.as_bytes())
+            .await?;
+        self.write_atomic(
+            &self.repo_path.join(MERGE_CONFLICTS_FILE),
+            conflicts.join("\n").as_bytes(),
+        )
+        .await?;
+        for path in &conflicts {
+            say!(self, "CONFLICT: {}", path);
+        }
+        Ok(MergeResult {
+            commit: None,
+            conflicts,
+        })
+    }
+
+    /// The commit being merged in and the paths left conflicted, while a
+    /// merge stopped by conflicts waits to be committed or aborted.
+    async fn read_merge_state(&self) -> Result<Option<(String, Vec<String>)>> {
+        let merge_head: String =
+            match fs::read_to_string(self.repo_path.join(MERGE_HEAD_FILE)).await {
+                Ok(merge_head) => merge_head.trim().to_string(),
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+        let conflicts: String =
+            match fs::read_to_string(self.repo_path.join(MERGE_CONFLICTS_FILE)).await {
+                Ok(conflicts) => conflicts,
+                Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(err.into()),
+            };
+        Ok(Some((
+            merge_head,
+            conflicts.lines().map(str::to_string).collect(),
+        )))
+    }
+
+    async fn clear_merge_state(&self) -> Result<()> {
+        for name in [MERGE_HEAD_FILE, MERGE_CONFLICTS_FILE] {
+            match fs::remove_file(self.repo_path.join(name)).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// The commit being merged while a conflicted merge is in progress.
+    pub async fn merge_head(&self) -> Result<Option<String>> {
+        Ok(self
+            .read_merge_state()
+            .await?
+            .map(|(merge_head, _)| merge_head))
+    }
+
+    /// Backs out of a merge stopped by conflicts: every path the merge
+    /// staged or left conflicted gets HEAD's version back, files HEAD does
+    /// not have are removed, the index is cleared and the merge state is
+    /// dropped. Other working-tree files are left alone.
+    pub async fn merge_abort(&mut self) -> Result<()> {
+        self.require_work_tree("abort a merge")?;
+        let _lock: IndexLock = self.lock_index().await?;
+        let Some((merge_head, conflicts)) = self.read_merge_state().await? else {
+            return Err(SgvcsError::InvalidInput("No merge in progress".to_string()));
+        };
+        let head_files: Vec<IndexData> = self.head_files().await?;
+        let mut paths: Vec<String> = self
+            .read_index()
+            .await?
+            .into_iter()
+            .map(|entry| entry.path)
+            .chain(conflicts)
+            .collect();
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            match head_files.iter().find(|file| file.path == path) {
+                Some(file) => {
+                    self.write_working_file(
+                        &file.path,
+                        &self.read_object(&file.hash).await?,
+                        file.mode,
+                    )
+                    .await?
+                }
+                None => {
+                    let working_path: PathBuf = self.working_path(&path)?;
+                    if working_path.exists() {
+                        fs::remove_file(&working_path).await?;
+                    }
+                }
+            }
+        }
+        self.write_index(&[]).await?;
+        self.clear_merge_state().await?;
+        say!(
+            self,
+            "Aborted the merge of {}",
+            self.short_hash(&merge_head).await?
+        );
+        Ok(())
+    }
+
+    /// Rewrites the working tree from `from` to `to`: changed and new files
+    /// are written, files missing from `to` are deleted.
+    async fn update_working_tree(&self, from: &[IndexData], to: &[IndexData]) -> Result<()> {
+        for file in to {
+            if !from
+                .iter()
+                .any(|old| old.path == file.path && old.hash == file.hash && old.mode == file.mode)
+            {
+                self.write_working_file(
+                    &file.path,
+                    &self.read_object(&file.hash).await?,
+                    file.mode,
+                )
+                .await?;
+            }
+        }
+        for file in from {
+            let path: PathBuf = self.working_path(&file.path)?;
+            if !to.iter().any(|new| new.path == file.path) && path.exists() {
+                fs::remove_file(&path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lowest common ancestor of two revisions, following every parent of
+    /// merge commits. When one is an ancestor of the other that one is
+    /// returned; unrelated histories give `None`.
+    pub async fn merge_base(&self, a: &str, b: &str) -> Result<Option<String>> {
+        let a: String = self.resolve_revision(a).await?;
+        let b: String = self.resolve_revision(b).await?;
+        let key: (String, String) = (a.clone(), b.clone());
+        if let Some(base) = self
+            .merge_bases
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            return Ok(base.clone());
+        }
+
+        let ancestors: HashSet<String> = self.ancestors(&a).await?;
+        // Breadth first from `b`, so the first shared commit is the nearest
+        let mut base: Option<String> = None;
+        let mut queue: VecDeque<String> = VecDeque::from([b]);
+        let mut seen: HashSet<String> = HashSet::new();
+        while let Some(hash) = queue.pop_front() {
+            if ancestors.contains(&hash) {
+                base = Some(hash);
+                break;
+            }
+            if seen.insert(hash.clone()) {
+                queue.extend(self.load_commit(&hash).await?.parent_hashes());
+            }
+        }
+
+        self.merge_bases
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, base.clone());
+        Ok(base)
+    }
+
+    /// Whether `maybe_ancestor` is `descendant` or reachable from it through
+    /// parents, i.e. whether moving a branch from `maybe_ancestor` to
+    /// `descendant` is a fast-forward. Both are revisions.
+    pub async fn is_ancestor(&self, maybe_ancestor: &str, descendant: &str) -> Result<bool> {
+        let maybe_ancestor: String = self.resolve_revision(maybe_ancestor).await?;
+        let descendant: String = self.resolve_revision(descendant).await?;
+        self.reaches(&descendant, &maybe_ancestor).await
+    }
+
+    /// Walks history from the commit `from` and stops as soon as the commit
+    /// `target` is found. `target` need not exist in this repo.
+    async fn reaches(&self, from: &str, target: &str) -> Result<bool> {
+        let mut pending: Vec<String> = vec![from.to_string()];
+        let mut seen: HashSet<String> = HashSet::new();
+        while let Some(hash) = pending.pop() {
+            if hash == target {
+                return Ok(true);
+            }
+            if seen.insert(hash.clone()) {
+                pending.extend(self.load_commit(&hash).await?.parent_hashes());
+            }
+        }
+        Ok(false)
+    }
+
+    /// `hash` and every commit reachable from it.
+    async fn ancestors(&self, hash: &str) -> Result<HashSet<String>> {
+        let mut ancestors: HashSet<String> = HashSet::new();
+        let mut pending: Vec<String> = vec![hash.to_string()];
+        while let Some(hash) = pending.pop() {
+            if ancestors.contains(&hash) {
+                continue;
+            }
+            pending.extend(self.load_commit(&hash).await?.parent_hashes());
+            ancestors.insert(hash);
+        }
+        Ok(ancestors)
+    }
+
+    /// Starts looking for the first bad commit between `good`, known to work,
+    /// and `bad`, a descendant of it known to be broken, then checks out the
+    /// commit halfway between them to test. The working tree must be clean.
+    pub async fn bisect_start(&mut self, good: &str, bad: &str) -> Result<BisectStep> {
+        self.require_work_tree("bisect")?;
+        if self.bisect_path().exists() {
+            return Err(SgvcsError::InvalidInput(
+                "A bisect is already in progress: reset it first".to_string(),
+            ));
+        }
+        let good: String = self.resolve_revision(good).await?;
+        let bad: String = self.resolve_revision(bad).await?;
+        if good == bad || !self.reaches(&bad, &good).await? {
+            return Err(SgvcsError::InvalidInput(format!(
+                "{} is not an ancestor of {}",
+                self.short_hash(&good).await?,
+                self.short_hash(&bad).await?
+            )));
+        }
+        if !self.is_clean().await? {
+            return Err(SgvcsError::InvalidInput(
+                "Local changes would be overwritten by bisect: commit them first".to_string(),
+            ));
+        }
+        let original: String = match self.current_branch().await {
+            Some(branch) => branch,
+            None => self.get_current_head().await?,
+        };
+        let state: BisectState = BisectState {
+            original,
+            bad,
+            good: vec![good],
+        };
+        self.bisect_step(state).await
+    }
+
+    /// Marks the commit being tested as good and moves on to the next one.
+    pub async fn bisect_good(&mut self) -> Result<BisectStep> {
+        self.require_work_tree("bisect")?;
+        let mut state: BisectState = self.read_bisect_state().await?;
+        state.good.push(self.get_current_head().await?);
+        self.bisect_step(state).await
+    }
+
+    /// Marks the commit being tested as bad and moves on to the next one.
+    pub async fn bisect_bad(&mut self) -> Result<BisectStep> {
+        self.require_work_tree("bisect")?;
+        let mut state: BisectState = self.read_bisect_state().await?;
+        state.bad = self.get_current_head().await?;
+        self.bisect_step(state).await
+    }
+
+    /// Ends the bisect and checks out what HEAD was on before it started.
+    pub async fn bisect_reset(&mut self) -> Result<()> {
+        self.require_work_tree("bisect")?;
+        let state: BisectState = self.read_bisect_state().await?;
+        self.checkout(&state.original, false).await?;
+        fs::remove_file(self.bisect_path()).await?;
+        Ok(())
+    }
+
+    /// Checks out the candidate that best halves the remaining range (the
+    /// commits reachable from the bad one but from no good one), then saves
+    /// `state`. A checkout that fails leaves the saved state as it was.
+    async fn bisect_step(&mut self, state: BisectState) -> Result<BisectStep> {
+        let mut known_good: HashSet<String> = HashSet::new();
+        for good in &state.good {
+            known_good.extend(self.ancestors(good).await?);
+        }
+        // Breadth first from the bad commit, so ties go to the newer commit
+        let mut candidates: Vec<String> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut queue: VecDeque<String> = VecDeque::from([state.bad.clone()]);
+        while let Some(hash) = queue.pop_front() {
+            if known_good.contains(&hash) || !seen.insert(hash.clone()) {
+                continue;
+            }
+            queue.extend(self.load_commit(&hash).await?.parent_hashes());
+            candidates.push(hash);
+        }
+        if candidates.len() <= 1 {
+            self.write_bisect_state(&state).await?;
+            say!(self, "{} is the first bad commit", state.bad);
+            return Ok(BisectStep::Found(state.bad));
+        }
+
+        let remaining: usize = candidates.len();
+        let mut best: (usize, &String) = (0, &candidates[0]);
+        for hash in &candidates {
+            let below: usize = self
+                .ancestors(hash)
+                .await?
+                .iter()
+                .filter(|ancestor| seen.contains(*ancestor))
+                .count();
+            let score: usize = below.min(remaining - below);
+            if score > best.0 {
+                best = (score, hash);
+            }
+        }
+        let commit: String = best.1.clone();
+        self.checkout(&commit, false).await?;
+        self.write_bisect_state(&state).await?;
+        say!(
+            self,
+            "Bisecting: {} commits left to test after this one",
+            remaining - 1
+        );
+        Ok(BisectStep::Testing { commit, remaining })
+    }
+
+    fn bisect_path(&self) -> PathBuf {
+        self.repo_path.join(BISECT_FILE)
+    }
+
+    async fn write_bisect_state(&self, state: &BisectState) -> Result<()> {
+        self.write_atomic(
+            &self.bisect_path(),
+            serde_json::to_string_pretty(state)?.as_bytes(),
+        )
+        .await
+    }
+
+    async fn read_bisect_state(&self) -> Result<BisectState> {
+        match fs::read_to_string(self.bisect_path()).await {
+            Ok(buffer) => Ok(serde_json::from_str(&buffer)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Err(SgvcsError::InvalidInput(
+                "No bisect in progress".to_string(),
+            )),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Reads a `key = value` entry from `.sgvcs/config`.
+    pub async fn get_config(&self, key: &str) -> Result<Option<String>> {
+        let entries: Vec<(String, String)> = self.read_config().await?;
+        Ok(entries
+            .into_iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Sets a `key = value` entry in `.sgvcs/config`, replacing any existing
+    /// value for the key. `core.objectsdir` can only be set while the object
+    /// store is still empty, and takes effect the next time the repo is
+    /// opened.
+    pub async fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        if key.is_empty() || key.contains('=') || key.contains(char::is_whitespace) {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Invalid config key {:?}",
+                key
+            )));
+        }
+        if value.contains('\n') {
+            return Err(SgvcsError::InvalidInput(
+                "Config values cannot span multiple lines".to_string(),
+            ));
+        }
+        if [HASH_ALGO_KEY, FORMAT_VERSION_KEY, CREATED_KEY, BARE_KEY].contains(&key) {
+            return Err(SgvcsError::InvalidInput(format!(
+                "{} is fixed when the repository is created",
+                key
+            )));
+        }
+        if key == AUTOCRLF_KEY {
+            AutoCrlf::parse(value.trim())?;
+        }
+        if key == ABBREV_KEY {
+            Self::parse_abbrev(value)?;
+        }
+        if key == REFLOG_KEEP_KEY || key == STASH_KEEP_KEY {
+            Self::parse_keep(key, value)?;
+        }
+        if key == OBJECTS_DIR_KEY {
+            Self::objects_dir_setting(value.trim())?;
+            if self.iter_objects().next().transpose()?.is_some() {
+                return Err(SgvcsError::InvalidInput(format!(
+                    "{} cannot change once objects have been written",
+                    key
+                )));
+            }
+        }
+        self.write_config(key, value).await
+    }
+
+    async fn write_config(&self, key: &str, value: &str) -> Result<()> {
+        let mut entries: Vec<(String, String)> = self.read_config().await?;
+        match entries.iter_mut().find(|(name, _)| name == key) {
+            Some(entry) => entry.1 = value.trim().to_string(),
+            None => entries.push((key.to_string(), value.trim().to_string())),
+        }
+        self.write_config_entries(&entries).await
+    }
+
+    async fn unset_config(&self, key: &str) -> Result<()> {
+        let mut entries: Vec<(String, String)> = self.read_config().await?;
+        entries.retain(|(name, _)| name != key);
+        self.write_config_entries(&entries).await
+    }
+
+    async fn write_config_entries(&self, entries: &[(String, String)]) -> Result<()> {
+        let contents: String = entries
+            .iter()
+            .map(|(name, value)| format!("{} = {}\n", name, value))
+            .collect();
+        self.write_atomic(&self.config_path, contents.as_bytes())
+            .await
+    }
+
+    async fn read_config(&self) -> Result<Vec<(String, String)>> {
+        let contents: String = match fs::read_to_string(&self.config_path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect())
+    }
+
+    pub async fn status_report(&self) -> Result<StatusReport> {
+        let ignore: IgnoreSet = self.load_ignore().await?;
+        let autocrlf: AutoCrlf = self.autocrlf().await?;
+        let index: Vec<IndexData> = self.read_index().await?;
+        let committed: HashMap<String, String> = self
+            .head_files()
+            .await?
+            .into_iter()
+            .map(|file| (file.path, file.hash))
+            .collect();
+
+        let mut report: StatusReport = StatusReport::default();
+        let mut staged: HashMap<String, String> = HashMap::new();
+        for entry in index {
+            if committed.get(&entry.path) != Some(&entry.hash) {
+                report.staged.push(entry.path.clone());
+            }
+            staged.insert(entry.path, entry.hash);
+        }
+        for path in self.walk_files(&self.root_path, &ignore).await? {
+            let key: String = Self::path_key(&path)?;
+            match staged.get(&key).or_else(|| committed.get(&key)) {
+                // Its deletion is staged, so the file on disk is untracked
+                Some(hash) if hash.is_empty() => report.untracked.push(key),
+                Some(hash) => {
+                    let content: Vec<u8> = fs::read(self.root_path.join(&path)).await?;
+                    if !self.blob_matches(hash, &autocrlf.clean(&content)) {
+                        report.modified.push(key);
+                    }
+                }
+                None => report.untracked.push(key),
+            }
+        }
+        let mut tracked: Vec<&String> = committed
+            .keys()
+            .chain(staged.keys())
+            .filter(|path| staged.get(*path).is_none_or(|hash| !hash.is_empty()))
+            .collect();
+        tracked.sort();
+        tracked.dedup();
+        for path in tracked {
+            if !self.root_path.join(path).exists() {
+                report.deleted.push(path.clone());
+            }
+        }
+        Ok(report)
+    }
+
+    /// Whether nothing is staged and every file tracked by HEAD still has its
+    /// committed content. Untracked files do not count. Uses the same
+    /// comparison as [`Sgvcs::status_report`] but stops at the first
+    /// difference.
+    pub async fn is_clean(&self) -> Result<bool> {
+        let committed: Vec<IndexData> = self.head_files().await?;
+        for entry in self.read_index().await? {
+            if !committed
+                .iter()
+                .any(|file| file.path == entry.path && file.hash == entry.hash)
+            {
+                return Ok(false);
+            }
+        }
+        let autocrlf: AutoCrlf = self.autocrlf().await?;
+        for file in committed {
+            match fs::read(self.root_path.join(&file.path)).await {
+                Ok(content) if self.blob_matches(&file.hash, &autocrlf.clean(&content)) => {}
+                Ok(_) => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// [`Sgvcs::status_report`] as a JSON object.
+    pub async fn status_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.status_report().await?)?)
+    }
+
+    pub async fn status(&self) -> Result<()> {
+        let report: StatusReport = self.status_report().await?;
+        let sections = [
+            ("Changes to be committed:", &report.staged),
+            ("Changes not staged for commit:", &report.modified),
+            ("Deleted, not staged for commit:", &report.deleted),
+            ("Untracked files:", &report.untracked),
+        ];
+        for (title, paths) in sections {
+            if paths.is_empty() {
+                continue;
+            }
+            say!(self, "{}", title);
+            for path in paths {
+                say!(self, "    {}", path);
+            }
+        }
+        if report.staged.is_empty()
+            && report.modified.is_empty()
+            && report.deleted.is_empty()
+            && report.untracked.is_empty()
+        {
+            say!(self, "Nothing to commit, working tree clean");
+        }
+        Ok(())
+    }
+
+    pub async fn branch(&self, name: &str) -> Result<()> {
+        Self::validate_ref_name(name)?;
+        let head: String = self.get_current_head().await?;
+        if head.is_empty() {
+            return Err(SgvcsError::NoCommits);
+        }
+        let branch_path: PathBuf = self.heads_path.join(name);
+        if branch_path.exists() {
+            return Err(SgvcsError::AlreadyExists(format!(
+                "Branch {:?} already exists",
+                name
+            )));
+        }
+        self.write_atomic(&branch_path, head.as_bytes()).await?;
+        say!(self, "Created branch {} at {}", name, head);
+        Ok(())
+    }
+
+    /// Creates branch `name` at the current commit and switches HEAD to it.
+    /// The working tree, index and any local changes are left as they are,
+    /// since the new branch starts where HEAD already is. On an unborn HEAD
+    /// only HEAD changes, and the branch comes into existence with the first
+    /// commit.
+    pub async fn checkout_new_branch(&mut self, name: &str) -> Result<()> {
+        self.require_work_tree("checkout")?;
+        let head: String = self.get_current_head().await?;
+        if head.is_empty() {
+            Self::validate_ref_name(name)?;
+            if self.heads_path.join(name).exists() {
+                return Err(SgvcsError::AlreadyExists(format!(
+                    "Branch {:?} already exists",
+                    name
+                )));
+            }
+        } else {
+            self.branch(name).await?;
+        }
+        self.set_head(name).await?;
+        if !head.is_empty() {
+            self.record_reflog(&head, &head, &format!("checkout: moving to {}", name))
+                .await?;
+        }
+        say!(self, "Switched to a new branch {:?}", name);
+        Ok(())
+    }
+
+    /// Points HEAD at an existing branch. With `checkout_files` this is a
+    /// checkout of the branch, refusing to overwrite local changes; without
+    /// it only HEAD moves, and the index and working tree stay as they are,
+    /// so they show up as changes against the new branch.
+    pub async fn switch(&mut self, branch: &str, checkout_files: bool) -> Result<()> {
+        Self::validate_ref_name(branch)?;
+        let branch_path: PathBuf = self.heads_path.join(branch);
+        if !branch_path.is_file() {
+            return Err(SgvcsError::NotFound(format!(
+                "Branch {:?} not found",
+                branch
+            )));
+        }
+        if checkout_files {
+            return self.checkout(branch, false).await;
+        }
+        let old: String = self.get_current_head().await?;
+        let new: String = fs::read_to_string(&branch_path).await?.trim().to_string();
+        self.set_head(branch).await?;
+        self.record_reflog(&old, &new, &format!("switch: moving to {}", branch))
+            .await?;
+        say!(self, "Switched HEAD to branch {:?}", branch);
+        Ok(())
+    }
+
+    /// Makes HEAD a symbolic ref to `branch`, which need not exist yet.
+    async fn set_head(&self, branch: &str) -> Result<()> {
+        self.write_atomic(
+            &self.head_path,
+            format!("{}{}", HEAD_REF_PREFIX, branch).as_bytes(),
+        )
+        .await
+    }
+
+    pub async fn list_branches(&self) -> Result<Vec<String>> {
+        Self::list_refs(&self.heads_path).await
+    }
+
+    /// Creates a lightweight tag pointing at `commit`, or at HEAD when no
+    /// commit is given.
+    pub async fn tag(&self, name: &str, commit: Option<&str>) -> Result<()> {
+        Self::validate_ref_name(name)?;
+        let target: String = match commit {
+            Some(commit) => {
+                let hash: String = self.resolve_revision(commit).await?;
+                self.load_commit(&hash).await?;
+                hash
+            }
+            None => self.get_current_head().await?,
+        };
+        if target.is_empty() {
+            return Err(SgvcsError::NoCommits);
+        }
+        let tag_path: PathBuf = self.tags_path.join(name);
+        if tag_path.exists() {
+            return Err(SgvcsError::AlreadyExists(format!(
+                "Tag {:?} already exists",
+                name
+            )));
+        }
+        self.write_atomic(&tag_path, target.as_bytes()).await?;
+        say!(self, "Created tag {} at {}", name, target);
+        Ok(())
+    }
+
+    pub async fn list_tags(&self) -> Result<Vec<String>> {
+        Self::list_refs(&self.tags_path).await
+    }
+
+    pub async fn delete_tag(&self, name: &str) -> Result<()> {
+        Self::validate_ref_name(name)?;
+        match fs::remove_file(self.tags_path.join(name)).await {
+            Ok(()) => {
+                say!(self, "Deleted tag {}", name);
+                Ok(())
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Err(SgvcsError::NotFound(format!("Tag {:?} not found", name)))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Names `commit` relative to the nearest tag reachable from it, as
+    /// `<tag>-<commits since>-g<short hash>`, or just `<tag>` when the commit
+    /// is tagged itself. Ties in distance go to the first tag by name. With
+    /// no reachable tag this is the short hash.
+    pub async fn describe(&self, commit: &str) -> Result<String> {
+        let hash: String = self.resolve_revision(commit).await?;
+        let mut tags: HashMap<String, String> = HashMap::new();
+        for name in self.list_tags().await? {
+            let target: String = fs::read_to_string(self.tags_path.join(&name)).await?;
+            // list_tags is sorted, so the first name per commit wins
+            tags.entry(target.trim().to_string()).or_insert(name);
+        }
+        let mut queue: VecDeque<(String, usize)> = VecDeque::from([(hash.clone(), 0)]);
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut nearest: Option<(usize, &String)> = None;
+        while let Some((current, distance)) = queue.pop_front() {
+            if nearest.is_some_and(|(best, _)| distance > best) {
+                break;
+            }
+            if !seen.insert(current.clone()) {
+                continue;
+            }
+            if let Some(name) = tags.get(&current) {
+                if nearest.is_none_or(|(_, best)| name < best) {
+                    nearest = Some((distance, name));
+                }
+                continue;
+            }
+            for parent in self.load_commit(&current).await?.parent_hashes() {
+                queue.push_back((parent, distance + 1));
+            }
+        }
+        Ok(match nearest {
+            Some((0, name)) => name.clone(),
+            Some((distance, name)) => {
+                format!("{}-{}-g{}", name, distance, self.short_hash(&hash).await?)
+            }
+            None => self.short_hash(&hash).await?,
+        })
+    }
+
+    async fn list_refs(dir: &Path) -> Result<Vec<String>> {
+        let mut names: Vec<String> = Vec::new();
+        let mut entries = match fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(names),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Turns anything that names a commit into its full hash: `HEAD`, a
+    /// branch, a tag, or a (possibly abbreviated) object hash. HEAD or the
+    /// current branch before its first commit is [`SgvcsError::NoCommits`].
+    pub async fn resolve_revision(&self, revision: &str) -> Result<String> {
+        if revision == "HEAD" {
+            let head: String = self.get_current_head().await?;
+            if head.is_empty() {
+                return Err(SgvcsError::NoCommits);
+            }
+            return Ok(head);
+        }
+        if Self::validate_ref_name(revision).is_ok() {
+            for dir in [&self.heads_path, &self.tags_path] {
+                let ref_path: PathBuf = dir.join(revision);
+                if ref_path.is_file() {
+                    return Ok(fs::read_to_string(&ref_path).await?.trim().to_string());
+                }
+            }
+            if self.current_branch().await.as_deref() == Some(revision) {
+                return Err(SgvcsError::NoCommits);
+            }
+        }
+        self.resolve_hash(revision).await
+    }
+
+    fn validate_ref_name(name: &str) -> Result<()> {
+        let invalid: bool = name.is_empty()
+            || name.starts_with('.')
+            || name
+                .chars()
+                .any(|c| c == '/' || c == '\\' || c.is_whitespace() || c.is_control());
+        if invalid {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Invalid ref name {:?}",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// The commit HEAD points at, following the current branch, or `None`
+    /// while there are no commits yet.
+    pub async fn head_commit(&self) -> Result<Option<CommitData>> {
+        let head: String = self.get_current_head().await?;
+        if head.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.get_commit_data(head).await?))
+    }
+
+    /// Files of [`Sgvcs::head_commit`], empty before the first commit.
+    async fn head_files(&self) -> Result<Vec<IndexData>> {
+        Ok(self
+            .head_commit()
+            .await?
+            .map(|commit| commit.files)
+            .unwrap_or_default())
+    }
+
+    /// Resolves HEAD to a commit hash, following a `ref: ` indirection. An
+    /// unborn branch or missing HEAD resolves to an empty string; any other
+    /// failure to read them is an error.
+    async fn get_current_head(&self) -> Result<String> {
+        let head: String = Self::read_if_exists(&self.head_path).await?;
+        match head.trim().strip_prefix("ref: ") {
+            Some(reference) => Ok(Self::read_if_exists(&self.repo_path.join(reference))
+                .await?
+                .trim()
+                .to_string()),
+            None => Ok(head.trim().to_string()),
+        }
+    }
+
+    /// Contents of `path`, or an empty string when it does not exist.
+    async fn read_if_exists(path: &Path) -> Result<String> {
+        match fs::read_to_string(path).await {
+            Ok(contents) => Ok(contents),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(String::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Moves whatever HEAD points at (the current branch, or HEAD itself when
+    /// it holds a raw hash) to `commit_hash`, recording `operation` in the
+    /// reflog.
+    async fn update_head(&self, commit_hash: &str, operation: &str) -> Result<()> {
+        let old: String = self.get_current_head().await?;
+        let head: String = fs::read_to_string(&self.head_path).await?;
+        match head.trim().strip_prefix("ref: ") {
+            Some(reference) => {
+                self.write_atomic(&self.repo_path.join(reference), commit_hash.as_bytes())
+                    .await?
+            }
+            None => {
+                self.write_atomic(&self.head_path, commit_hash.as_bytes())
+                    .await?
+            }
+        }
+        self.record_reflog(&old, commit_hash, operation).await
+    }
+
+    /// Appends `<old> <new> <time> <operation>` to `.sgvcs/logs/HEAD`, plus
+    /// `\tbranch=<name>` when HEAD is on a branch. An unborn HEAD is written
+    /// as all zeros.
+    async fn record_reflog(&self, old: &str, new: &str, operation: &str) -> Result<()> {
+        let old: String = if old.is_empty() {
+            "0".repeat(new.len())
+        } else {
+            old.to_string()
+        };
+        let branch: String = match self.current_branch().await {
+            Some(branch) => format!("\t{}{}", REFLOG_BRANCH_PREFIX, branch),
+            None => String::new(),
+        };
+        let line: String = format!(
+            "{} {} {} {}{}\n",
+            old,
+            new,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            operation,
+            branch
+        );
+        if let Some(logs) = self.reflog_path.parent() {
+            fs::create_dir_all(logs).await?;
+        }
+        let mut log: fs::File = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.reflog_path)
+            .await?;
+        log.write_all(line.as_bytes()).await?;
+        log.flush().await?;
+        Ok(())
+    }
+
+    /// Every recorded HEAD movement, oldest first. `old` is empty for the
+    /// first commit on an unborn branch.
+    pub async fn reflog(&self) -> Result<Vec<ReflogEntry>> {
+        let contents: String = match fs::read_to_string(&self.reflog_path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(4, ' ');
+                let old: &str = fields.next()?;
+                let new: &str = fields.next()?;
+                let timestamp: &str = fields.next()?;
+                let rest: &str = fields.next().unwrap_or_default();
+                let (operation, branch) = match rest.rsplit_once('\t') {
+                    Some((operation, hint)) if hint.starts_with(REFLOG_BRANCH_PREFIX) => (
+                        operation,
+                        Some(hint[REFLOG_BRANCH_PREFIX.len()..].to_string()),
+                    ),
+                    _ => (rest, None),
+                };
+                Some(ReflogEntry {
+                    old: if old.chars().all(|c| c == '0') {
+                        String::new()
+                    } else {
+                        old.to_string()
+                    },
+                    new: new.to_string(),
+                    timestamp: timestamp.to_string(),
+                    operation: operation.to_string(),
+                    branch,
+                })
+            })
+            .collect())
+    }
+
+    /// Drops all but the newest `keep` reflog entries and returns how many
+    /// were dropped.
+    pub async fn prune_reflog(&self, keep: usize) -> Result<usize> {
+        let contents: String = match fs::read_to_string(&self.reflog_path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let lines: Vec<&str> = contents.lines().collect();
+        if lines.len() <= keep {
+            return Ok(0);
+        }
+        let dropped: usize = lines.len() - keep;
+        let mut kept: String = String::new();
+        for line in &lines[dropped..] {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+        self.write_atomic(&self.reflog_path, kept.as_bytes())
+            .await?;
+        say!(self, "Pruned {} old reflog entries", dropped);
+        Ok(dropped)
+    }
+
+    /// Trims the reflog and the stash to the counts configured as
+    /// `gc.reflogkeep` and `gc.stashkeep`, by default 100 and 20 entries.
+    /// Returns how many reflog and stash entries were dropped.
+    pub async fn prune(&self) -> Result<(usize, usize)> {
+        let reflog_keep: usize = self
+            .keep_setting(REFLOG_KEEP_KEY, DEFAULT_REFLOG_KEEP)
+            .await?;
+        let stash_keep: usize = self
+            .keep_setting(STASH_KEEP_KEY, DEFAULT_STASH_KEEP)
+            .await?;
+        Ok((
+            self.prune_reflog(reflog_keep).await?,
+            self.expire_stash(stash_keep).await?,
+        ))
+    }
+
+    async fn keep_setting(&self, key: &str, default: usize) -> Result<usize> {
+        match self.get_config(key).await? {
+            Some(value) => Self::parse_keep(key, &value),
+            None => Ok(default),
+        }
+    }
+
+    fn parse_keep(key: &str, value: &str) -> Result<usize> {
+        value.trim().parse().map_err(|_| {
+            SgvcsError::InvalidInput(format!("{} must be a count, not {:?}", key, value))
+        })
+    }
+
+    /// Prints history from HEAD, newest first. `oneline` prints one
+    /// `<short-hash> <message>` line per commit.
+    pub async fn log(&self, limit: Option<usize>, oneline: bool) -> Result<()> {
+        for entry in self.log_entries(limit).await? {
+            if oneline {
+                say!(
+                    self,
+                    "{} {}",
+                    self.short_hash(&entry.hash).await?,
+                    Self::subject(&entry.message)
+                );
+            } else {
+                say!(self, "\nCommit: {}", entry.hash);
+                say!(self, "author: {} <{}>", entry.author, entry.email);
+                say!(self, "message: {:?}", entry.message);
+                say!(self, "time_stamp: {:?}", entry.timestamp);
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the parent chain from HEAD, stopping after `limit` commits. A
+    /// chain that leads back to a commit already visited is corrupt and
+    /// reported as an error instead of looping forever.
+    pub async fn log_entries(&self, limit: Option<usize>) -> Result<Vec<LogEntry>> {
+        let mut entries: Vec<LogEntry> = Vec::new();
+        let mut history: History = self.history(&self.get_current_head().await?);
+        while limit.is_none_or(|limit| entries.len() < limit) {
+            let Some(next) = history.next().await else {
+                break;
+            };
+            let (hash, commit) = next?;
+            entries.push(LogEntry {
+                time: commit.time(),
+                hash,
+                message: commit.message,
+                timestamp: commit.time_stamp,
+                parent: commit.parent,
+                author: commit.author,
+                email: commit.email,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// The whole log from HEAD as a JSON array of [`LogEntry`], newest first,
+    /// for tools that would otherwise scrape `log`.
+    pub async fn log_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(
+            &self.log_entries(None).await?,
+        )?)
+    }
+
+    /// Renders every commit reachable from HEAD or a branch as an ASCII graph,
+    /// newest first, one `<short-hash> <subject>` line per commit. Each line
+    /// of development gets a column; `*` marks the commit, `|` a line passing
+    /// by, `\` a merge's extra parent branching off and `/` two lines joining
+    /// again where they share a parent. A commit is always shown above its
+    /// parents.
+    pub async fn log_graph(&self) -> Result<String> {
+        let mut tips: Vec<String> = vec![self.get_current_head().await?];
+        for name in Self::list_refs(&self.heads_path).await? {
+            tips.push(
+                fs::read_to_string(self.heads_path.join(name))
+                    .await?
+                    .trim()
+                    .to_string(),
+            );
+        }
+        let mut commits: HashMap<String, CommitData> = HashMap::new();
+        let mut pending: Vec<String> = tips.into_iter().filter(|tip| !tip.is_empty()).collect();
+        while let Some(hash) = pending.pop() {
+            if let Entry::Vacant(slot) = commits.entry(hash) {
+                let commit: CommitData = self.load_commit(slot.key()).await?;
+                pending.extend(commit.parent_hashes());
+                slot.insert(commit);
+            }
+        }
+
+        // Newest first among the commits whose children are all shown
+        let mut children: HashMap<&str, usize> = HashMap::new();
+        for commit in commits.values() {
+            for parent in commit.parent_hashes() {
+                if let Some((parent, _)) = commits.get_key_value(&parent) {
+                    *children.entry(parent.as_str()).or_default() += 1;
+                }
+            }
+        }
+        let mut ready: BinaryHeap<(Option<DateTime<Utc>>, &str)> = commits
+            .iter()
+            .filter(|(hash, _)| !children.contains_key(hash.as_str()))
+            .map(|(hash, commit)| (commit.time(), hash.as_str()))
+            .collect();
+
+        let mut output: String = String::new();
+        let mut columns: Vec<String> = Vec::new();
+        while let Some((_, hash)) = ready.pop() {
+            let commit: &CommitData = &commits[hash];
+            let parents: Vec<String> = commit.parent_hashes();
+            for parent in &parents {
+                if let Some((parent, commit)) = commits.get_key_value(parent) {
+                    let count: &mut usize = children.entry(parent.as_str()).or_default();
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push((commit.time(), parent.as_str()));
+                    }
+                }
+            }
+
+            let column: usize = match columns.iter().position(|expected| expected == hash) {
+                Some(column) => column,
+                None => {
+                    columns.push(hash.to_string());
+                    columns.len() - 1
+                }
+            };
+            let marks: Vec<&str> = (0..columns.len())
+                .map(|i| if i == column { "*" } else { "|" })
+                .collect();
+            output.push_str(&format!(
+                "{} {} {}\n",
+                marks.join(" "),
+                self.short_hash(hash).await?,
+                Self::subject(&commit.message)
+            ));
+
+            // Where each column goes on the next line: the commit's column
+            // continues as its parents, and a line expecting a commit that
+            // another line already expects joins it
+            let mut next: Vec<String> = Vec::new();
+            let mut moves: Vec<(usize, usize)> = Vec::new();
+            for (i, expected) in columns.iter().enumerate() {
+                let continues: Vec<&String> = if i == column {
+                    parents.iter().collect()
+                } else {
+                    vec![expected]
+                };
+                for expected in continues {
+                    match next.iter().position(|other| other == expected) {
+                        Some(to) => moves.push((i, to)),
+                        None => {
+                            moves.push((i, next.len()));
+                            next.push(expected.clone());
+                        }
+                    }
+                }
+            }
+            let mut edges: Vec<char> = vec![' '; 2 * columns.len().max(next.len())];
+            for (from, to) in moves {
+                match to.cmp(&from) {
+                    Ordering::Equal => edges[2 * from] = '|',
+                    Ordering::Greater => edges[2 * from + 1] = '\\',
+                    Ordering::Less => edges[2 * from - 1] = '/',
+                }
+            }
+            let edges: String = edges.into_iter().collect::<String>().trim_end().to_string();
+            if edges.contains(['/', '\\']) {
+                output.push_str(&edges);
+                output.push('\n');
+            }
+            columns = next;
+        }
+        Ok(output)
+    }
+
+    /// Walks history from `start` (any revision) back to the root commit,
+    /// loading each commit only when it is reached. An empty `start` yields
+    /// nothing, matching an unborn HEAD.
+    pub fn history(&self, start: &str) -> History<'_> {
+        History {
+            repo: self,
+            next: start.to_string(),
+            resolved: false,
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Hashes of the commits reachable from HEAD whose message contains
+    /// `pattern`, newest first. With `regex` the pattern is a regular
+    /// expression matched anywhere in the message instead.
+    pub async fn find_commits(&self, pattern: &str, regex: bool) -> Result<Vec<String>> {
+        let matcher: Option<Regex> = if regex {
+            Some(Regex::new(pattern).map_err(|err| {
+                SgvcsError::InvalidInput(format!("Invalid pattern {:?}: {}", pattern, err))
+            })?)
+        } else {
+            None
+        };
+        let mut found: Vec<String> = Vec::new();
+        let mut history: History = self.history(&self.get_current_head().await?);
+        while let Some(next) = history.next().await {
+            let (hash, commit) = next?;
+            let matched: bool = match &matcher {
+                Some(matcher) => matcher.is_match(&commit.message),
+                None => commit.message.contains(pattern),
+            };
+            if matched {
+                found.push(hash);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Abbreviates `hash` to its first `core.abbrev` characters (7 unless
+    /// set), adding more while that prefix also names another object, so the
+    /// result always resolves back with [`Sgvcs::resolve_hash`].
+    pub async fn short_hash(&self, hash: &str) -> Result<String> {
+        let mut len: usize = match self.get_config(ABBREV_KEY).await? {
+            Some(value) => Self::parse_abbrev(&value)?,
+            None => DEFAULT_ABBREV,
+        };
+        while len < hash.len() && self.objects.with_prefix(&hash[..len]).await?.len() > 1 {
+            len += 1;
+        }
+        Ok(hash[..len.min(hash.len())].to_string())
+    }
+
+    /// The first [`DEFAULT_ABBREV`] characters of `hash`, for output with no
+    /// repo at hand to make it unique, like [`CommitData`]'s `Display`.
+    fn abbrev(hash: &str) -> &str {
+        &hash[..hash.len().min(DEFAULT_ABBREV)]
+    }
+
+    fn parse_abbrev(value: &str) -> Result<usize> {
+        match value.trim().parse::<usize>() {
+            Ok(len) if len >= MIN_ABBREV => Ok(len),
+            _ => Err(SgvcsError::InvalidInput(format!(
+                "{} must be a length of at least {}, not {:?}",
+                ABBREV_KEY, MIN_ABBREV, value
+            ))),
+        }
+    }
+
+    /// Attributes each line of `path` as committed at HEAD to the commit that
+    /// last changed it. Lines are followed back along first parents with the
+    /// line diff, so a line stays with the commit that added it until a
+    /// later commit rewrites it. Binary files cannot be blamed.
+    pub async fn blame(&self, path: &Path) -> Result<Vec<BlameLine>> {
+        let key: String = Self::path_key(&self.relative_path(path)?)?;
+        let mut history: History = self.history(&self.get_current_head().await?);
+        let (mut hash, mut commit) = match history.next().await {
+            Some(next) => next?,
+            None => return Err(SgvcsError::NoCommits),
+        };
+        let mut blob: String = match Self::file_hash(&commit, &key) {
+            Some(blob) => blob,
+            None => {
+                return Err(SgvcsError::NotFound(format!(
+                    "{:?} does not exist in commit {}",
+                    key, hash
+                )))
+            }
+        };
+        let mut text: String = self.blame_text(&key, &blob).await?;
+        let lines: Vec<String> = text.lines().map(str::to_string).collect();
+        let mut blamed: Vec<Option<BlameLine>> = vec![None; lines.len()];
+        // (line in the HEAD version, same line in the version being examined)
+        let mut pending: Vec<(usize, usize)> = (0..lines.len()).map(|line| (line, line)).collect();
+
+        while !pending.is_empty() {
+            let parent: Option<(String, CommitData)> = match history.next().await {
+                Some(next) => Some(next?),
+                None => None,
+            };
+            let parent_blob: Option<String> = parent
+                .as_ref()
+                .and_then(|(_, parent)| Self::file_hash(parent, &key));
+            let mut parent_text: Option<String> = None;
+            let origins: Vec<Option<usize>> = match &parent_blob {
+                Some(parent_blob) if *parent_blob == blob => {
+                    (0..text.lines().count()).map(Some).collect()
+                }
+                Some(parent_blob) => {
+                    let older: String = self.blame_text(&key, parent_blob).await?;
+                    let origins: Vec<Option<usize>> = diff::line_origins(&older, &text);
+                    parent_text = Some(older);
+                    origins
+                }
+                None => vec![None; text.lines().count()],
+            };
+
+            let mut remaining: Vec<(usize, usize)> = Vec::new();
+            for (line, pos) in pending {
+                match origins[pos] {
+                    Some(origin) => remaining.push((line, origin)),
+                    None => {
+                        blamed[line] = Some(BlameLine {
+                            commit: hash.clone(),
+                            author: commit.author.clone(),
+                            text: lines[line].clone(),
+                        })
+                    }
+                }
+            }
+            pending = remaining;
+
+            // Lines only survive into a parent that has the file
+            if let (Some((parent_hash, parent)), Some(parent_blob)) = (parent, parent_blob) {
+                hash = parent_hash;
+                commit = parent;
+                blob = parent_blob;
+                if let Some(parent_text) = parent_text {
+                    text = parent_text;
+                }
+            }
+        }
+        Ok(blamed.into_iter().flatten().collect())
+    }
+
+    /// Commits on HEAD's first-parent history that changed `path`, newest
+    /// first: those where its blob differs from the parent's, including the
+    /// commits that added or deleted it. Empty when the path was never
+    /// tracked or there are no commits.
+    pub async fn file_history(&self, path: &Path) -> Result<Vec<String>> {
+        let key: String = Self::path_key(&self.relative_path(path)?)?;
+        let mut changed: Vec<String> = Vec::new();
+        let head: String = self.get_current_head().await?;
+        if head.is_empty() {
+            return Ok(changed);
+        }
+        // Each commit is compared with the next one the walk yields, its parent
+        let mut newer: Option<(String, Option<String>)> = None;
+        let mut history: History = self.history(&head);
+        while let Some(next) = history.next().await {
+            let (hash, commit) = next?;
+            let blob: Option<String> = Self::file_hash(&commit, &key);
+            if let Some((newer_hash, newer_blob)) = newer.take() {
+                if newer_blob != blob {
+                    changed.push(newer_hash);
+                }
+            }
+            newer = Some((hash, blob));
+        }
+        if let Some((root, Some(_))) = newer {
+            changed.push(root);
+        }
+        Ok(changed)
+    }
+
+    fn file_hash(commit: &CommitData, key: &str) -> Option<String> {
+        commit
+            .files
+            .iter()
+            .find(|file| file.path == key)
+            .map(|file| file.hash.clone())
+    }
+
+    async fn blame_text(&self, key: &str, blob: &str) -> Result<String> {
+        match diff::as_text(&self.read_object(blob).await?) {
+            Some(text) => Ok(text.into_owned()),
+            None => Err(SgvcsError::InvalidInput(format!(
+                "Cannot blame binary file {:?}",
+                key
+            ))),
+        }
+    }
+
+    pub async fn show_commit_diff(&self, commithash: String) {
+        let diff: Result<String> = if commithash.is_empty() {
+            Err(SgvcsError::NoCommits)
+        } else {
+            self.diff_commit(&commithash).await
+        };
+        match diff {
+            Ok(diff) => self.emit(format_args!("{}", diff)),
+            Err(err) => say!(self, "{}", err),
+        }
+    }
+
+    /// A commit's header (hash, parents of a merge, author, time and message)
+    /// followed by its diff against the first parent, like `diff_commit`.
+    pub async fn show(&self, commit: &str) -> Result<String> {
+        let commit_hash: String = self.resolve_revision(commit).await?;
+        let commit: CommitData = self.load_commit(&commit_hash).await?;
+        let mut output: String = format!("Commit: {}\n", commit_hash);
+        if commit.parents.len() > 1 {
+            let mut parents: Vec<String> = Vec::new();
+            for parent in &commit.parents {
+                parents.push(self.short_hash(parent).await?);
+            }
+            output.push_str(&format!("Merge: {}\n", parents.join(" ")));
+        }
+        output.push_str(&format!("author: {} <{}>\n", commit.author, commit.email));
+        output.push_str(&format!("time_stamp: {}\n\n", commit.time_stamp));
+        for line in commit.subject().lines() {
+            output.push_str(&format!("    {}\n", line));
+        }
+        if !commit.body().is_empty() {
+            output.push_str("    \n");
+            for line in commit.body().lines() {
+                output.push_str(&format!("    {}\n", line));
+            }
+        }
+        output.push('\n');
+        let parent_files: Vec<IndexData> = self.commit_files(&commit.parent).await?;
+        output.push_str(
+            &self
+                .diff_files(&parent_files, &commit.files, &DiffOptions::default())
+                .await?,
+        );
+        Ok(output)
+    }
+
+    /// Unified diff of a commit against its parent. Files added in the commit
+    /// show every line as an addition; unchanged files are omitted.
+    pub async fn diff_commit(&self, commithash: &str) -> Result<String> {
+        self.diff_commit_with(commithash, &DiffOptions::default())
+            .await
+    }
+
+    /// Like [`Sgvcs::diff_commit`], with `options` deciding how lines are
+    /// compared.
+    pub async fn diff_commit_with(
+        &self,
+        commithash: &str,
+        options: &DiffOptions,
+    ) -> Result<String> {
+        let commit: CommitData = self.load_commit(commithash).await?;
+        let parent_files: Vec<IndexData> = self.commit_files(&commit.parent).await?;
+        self.diff_files(&parent_files, &commit.files, options).await
+    }
+
+    /// Unified diff of what the next commit would record against HEAD: each
+    /// staged file against the same path in HEAD. Files staged but not in
+    /// HEAD show as additions.
+    pub async fn staged_diff(&self) -> Result<String> {
+        let head_files: Vec<IndexData> = self.head_files().await?;
+        let staged_files: Vec<IndexData> = self.staged_files().await?;
+        self.diff_files(&head_files, &staged_files, &DiffOptions::default())
+            .await
+    }
+
+    /// Unified diff of the changes not yet staged: each tracked file in the
+    /// working tree against its staged version, or HEAD's when it is not
+    /// staged. Tracked files missing from disk show as deleted; untracked
+    /// files are left out.
+    pub async fn worktree_diff(&self) -> Result<String> {
+        let mut output: String = String::new();
+        let autocrlf: AutoCrlf = self.autocrlf().await?;
+        for file in self.staged_files().await? {
+            let staged: Vec<u8> = self.read_object(&file.hash).await?;
+            let path: PathBuf = self.working_path(&file.path)?;
+            let current: Option<Vec<u8>> = match fs::read(&path).await {
+                Ok(content) => {
+                    let content: Vec<u8> = autocrlf.clean(&content).into_owned();
+                    if self.blob_matches(&file.hash, &content) {
+                        continue;
+                    }
+                    Some(content)
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            output.push_str(&diff::file_diff(
+                &file.path,
+                Some(&staged),
+                current.as_deref(),
+            ));
+        }
+        Ok(output)
+    }
+
+    /// HEAD's files with the staged entries laid over them, which is what
+    /// committing now would record.
+    async fn staged_files(&self) -> Result<Vec<IndexData>> {
+        let mut files: Vec<IndexData> = self.head_files().await?;
+        Self::overlay_staged(&mut files, self.dedupe_entries(self.read_index().await?));
+        Ok(files)
+    }
+
+    /// Unified diff between two arbitrary commits, pairing files by path.
+    /// Files only in `from` show as deleted, files only in `to` as added,
+    /// except that a deleted and an added file with the same content show as
+    /// a rename.
+    pub async fn diff_commits(&self, from: &str, to: &str) -> Result<String> {
+        self.diff_commits_with(from, to, &DiffOptions::default())
+            .await
+    }
+
+    /// Like [`Sgvcs::diff_commits`], with `options` deciding how lines are
+    /// compared.
+    pub async fn diff_commits_with(
+        &self,
+        from: &str,
+        to: &str,
+        options: &DiffOptions,
+    ) -> Result<String> {
+        let from_commit: CommitData = self.load_commit(from).await?;
+        let to_commit: CommitData = self.load_commit(to).await?;
+        // The tree hash covers every path, hash and mode, so equal trees
+        // mean there is nothing to compare
+        if !from_commit.tree.is_empty() && from_commit.tree == to_commit.tree {
+            return Ok(String::new());
+        }
+        self.diff_files(&from_commit.files, &to_commit.files, options)
+            .await
+    }
+
+    async fn diff_files(
+        &self,
+        old_files: &[IndexData],
+        new_files: &[IndexData],
+        options: &DiffOptions,
+    ) -> Result<String> {
+        let mut deleted: Vec<&IndexData> = old_files
+            .iter()
+            .filter(|old| !new_files.iter().any(|file| file.path == old.path))
+            .collect();
+        // Blobs are content-addressed, so a path that vanished and one that
+        // appeared with the same hash are the same file moved
+        let mut renamed_from: HashMap<&str, &str> = HashMap::new();
+        for file in new_files {
+            if old_files.iter().any(|old| old.path == file.path) {
+                continue;
+            }
+            if let Some(pos) = deleted.iter().position(|old| old.hash == file.hash) {
+                renamed_from.insert(&file.path, &deleted.remove(pos).path);
+            }
+        }
+
+        let mut output: String = String::new();
+        for file in new_files {
+            if let Some(old_path) = renamed_from.get(file.path.as_str()) {
+                output.push_str(&diff::rename_header(old_path, &file.path));
+                continue;
+            }
+            let old_content: Option<Vec<u8>> =
+                match old_files.iter().find(|old| old.path == file.path) {
+                    Some(old) if old.hash == file.hash => continue,
+                    Some(old) => Some(self.get_file_contents(old.hash.clone()).await?),
+                    None => None,
+                };
+            let new_content: Vec<u8> = self.get_file_contents(file.hash.clone()).await?;
+            output.push_str(&diff::file_diff_with(
+                &file.path,
+                old_content.as_deref(),
+                Some(&new_content),
+                options,
+            ));
+        }
+        for old in deleted {
+            let old_content: Vec<u8> = self.get_file_contents(old.hash.clone()).await?;
+            output.push_str(&diff::file_diff_with(
+                &old.path,
+                Some(&old_content),
+                None,
+                options,
+            ));
+        }
+        Ok(output)
+    }
+
+    /// Returns the uncompressed contents of any object.
+    pub async fn cat_file(&self, hash: &str) -> Result<Vec<u8>> {
+        let hash: String = self.resolve_hash(hash).await?;
+        self.read_object(&hash).await
+    }
+
+    /// Whether an object named `hash` (a full hash) is in the store.
+    pub fn object_exists(&self, hash: &str) -> bool {
+        self.objects.contains(hash)
+    }
+
+    /// Size in bytes of the content of object `hash`, as [`Sgvcs::cat_file`]
+    /// would return it, not the compressed size on disk. Only the header is
+    /// decompressed, so large blobs are not inflated just to be measured.
+    pub async fn object_size(&self, hash: &str) -> Result<u64> {
+        let hash: String = self.resolve_hash(hash).await?;
+        let compressed: Vec<u8> = self.read_object_file(&hash).await?;
+        if let Some((_, len)) = Self::read_header(&compressed) {
+            return Ok(len as u64);
+        }
+        // Objects from before headers were added have to be measured
+        Ok(self.decompress(&hash, compressed)?.len() as u64)
+    }
+
+    /// Type and content length from the header of a compressed object,
+    /// decompressing only as much as the header needs. `None` for objects
+    /// without a header.
+    fn read_header(compressed: &[u8]) -> Option<(ObjectType, usize)> {
+        let mut start: Vec<u8> = Vec::with_capacity(HEADER_MAX_LEN);
+        ZlibDecoder::new(compressed)
+            .take(HEADER_MAX_LEN as u64)
+            .read_to_end(&mut start)
+            .ok()?;
+        ObjectType::parse_header(&start).map(|(kind, len, _)| (kind, len))
+    }
+
+    /// Counts the objects in the store by type, with their size on disk and
+    /// how many are unreachable, which is what [`Sgvcs::gc`] would remove.
+    pub async fn count_objects(&self) -> Result<ObjectStats> {
+        let reachable: HashSet<String> = self.reachable_objects().await?;
+        let mut stats: ObjectStats = ObjectStats::default();
+        for hash in self.iter_objects() {
+            let hash: String = hash?;
+            let compressed: Vec<u8> = self.read_object_file(&hash).await?;
+            stats.objects += 1;
+            stats.bytes += compressed.len() as u64;
+            let kind: ObjectType = match Self::read_header(&compressed) {
+                Some((kind, _)) => kind,
+                None => ObjectType::sniff(&self.decompress(&hash, compressed)?),
+            };
+            match kind {
+                ObjectType::Commit => stats.commits += 1,
+                ObjectType::Tree => stats.trees += 1,
+                ObjectType::Blob => stats.blobs += 1,
+            }
+            if !reachable.contains(&hash) {
+                stats.unreachable += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Expands an abbreviated object hash (at least four hex characters) to
+    /// the full hash of the single object it matches.
+    pub async fn resolve_hash(&self, prefix: &str) -> Result<String> {
+        let prefix: String = prefix.trim().to_ascii_lowercase();
+        if prefix.len() < MIN_ABBREV || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Not a valid object name {:?}",
+                prefix
+            )));
+        }
+        if self.objects.contains(&prefix) {
+            return Ok(prefix);
+        }
+
+        let mut matches: Vec<String> = self.objects.with_prefix(&prefix).await?;
+        match matches.len() {
+            0 => Err(SgvcsError::ObjectNotFound(prefix)),
+            1 => Ok(matches.remove(0)),
+            _ => {
+                matches.sort();
+                Err(SgvcsError::Ambiguous { prefix, matches })
+            }
+        }
+    }
+
+    /// The type recorded in an object's header. Objects from before headers
+    /// existed are classified by what their contents deserialize as, and
+    /// anything that is neither a commit nor a tree is a blob.
+    pub async fn cat_file_type(&self, hash: &str) -> Result<ObjectType> {
+        let hash: String = self.resolve_hash(hash).await?;
+        let content: Vec<u8> = self.read_stored_object(&hash).await?;
+        match ObjectType::split_header(&content) {
+            (Some(kind), _) => Ok(kind),
+            (None, _) => Ok(ObjectType::sniff(&content)),
+        }
+    }
+
+    /// Re-hashes every stored object and checks that commits and trees only
+    /// reference objects that exist. Problems are collected rather than
+    /// stopping at the first one.
+    pub async fn fsck(&self) -> Result<Vec<FsckError>> {
+        let mut problems: Vec<FsckError> = Vec::new();
+        for hash in self.object_hashes().await? {
+            let stored: Vec<u8> = match self.read_stored_object(&hash).await {
+                Ok(stored) => stored,
+                // Bytes that do not even decompress are reported by what they hash to
+                Err(SgvcsError::CorruptObject { .. }) => {
+                    let raw: Vec<u8> = self.read_object_file(&hash).await?;
+                    problems.push(FsckError::HashMismatch {
+                        hash: hash.clone(),
+                        actual: self.hash_stored(&raw),
+                    });
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let actual: String = self.hash_stored(&stored);
+            if actual != hash {
+                problems.push(FsckError::HashMismatch {
+                    hash: hash.clone(),
+                    actual,
+                });
+            }
+
+            // Only objects without a header have to be sniffed for their type
+            let (kind, offset) = ObjectType::split_header(&stored);
+            let content: &[u8] = &stored[offset..];
+            let may_be = |expected: ObjectType| kind.is_none_or(|kind| kind == expected);
+            let mut referenced: Vec<String> = Vec::new();
+            if let Some(commit) = serde_json::from_slice::<CommitData>(content)
+                .ok()
+                .filter(|_| may_be(ObjectType::Commit))
+            {
+                for parent in commit.parent_hashes() {
+                    if !self.object_exists(&parent) {
+                        problems.push(FsckError::DanglingParent {
+                            commit: hash.clone(),
+                            parent,
+                        });
+                    }
+                }
+                if !commit.tree.is_empty() {
+                    // Missing trees are reported below as missing objects
+                    if let Ok(files) = self.read_tree(&commit.tree).await {
+                        let actual: String = self.tree_objects(&files)?.0;
+                        let legacy: String = self.tree_objects_as(&files, pretty_tree)?.0;
+                        if actual != commit.tree && legacy != commit.tree {
+                            problems.push(FsckError::TreeMismatch {
+                                commit: hash.clone(),
+                                tree: commit.tree.clone(),
+                                actual,
+                            });
+                        }
+                    }
+                    referenced.push(commit.tree);
+                }
+                referenced.extend(commit.files.into_iter().map(|file| file.hash));
+            } else if let Some(entries) = serde_json::from_slice::<Vec<TreeEntry>>(content)
+                .ok()
+                .filter(|_| may_be(ObjectType::Tree))
+            {
+                referenced.extend(entries.into_iter().map(|entry| entry.hash));
+            }
+            for missing in referenced {
+                if !self.object_exists(&missing) {
+                    problems.push(FsckError::MissingObject {
+                        hash: missing,
+                        referenced_by: hash.clone(),
+                    });
+                }
+            }
+        }
+        for problem in &problems {
+            say!(self, "{}", problem);
+        }
+        Ok(problems)
+    }
+
+    /// Re-serializes the commit `hash` from its parsed fields and checks that
+    /// the result hashes to `hash`. `true` means the object is intact and
+    /// holds exactly the fields this build reads, nothing dropped or added,
+    /// so the message, author, time, parents and tree shown for it are what
+    /// was committed. Commits stored as pretty-printed JSON before the
+    /// canonical encoding was introduced are checked against that form.
+    /// Commits written before trees existed store their files inline and
+    /// cannot be reproduced, so they report `false`.
+    pub async fn verify_commit(&self, hash: &str) -> Result<bool> {
+        let hash: String = self.resolve_hash(hash).await?;
+        let stored: Vec<u8> = self.read_stored_object(&hash).await?;
+        let (kind, offset) = ObjectType::split_header(&stored);
+        if kind.is_some_and(|kind| kind != ObjectType::Commit) {
+            return Err(SgvcsError::InvalidInput(format!(
+                "{} is not a commit",
+                hash
+            )));
+        }
+        let commit: CommitData = Self::parse_object(&hash, &stored[offset..])?;
+        let encodings: [Vec<u8>; 2] = [
+            commit.canonical_bytes()?,
+            serde_json::to_vec_pretty(&commit)?,
+        ];
+        Ok(encodings.iter().any(|bytes| {
+            let rebuilt: String = match kind {
+                Some(kind) => self.hash(kind, bytes),
+                None => self.hash_stored(bytes),
+            };
+            rebuilt == hash
+        }))
+    }
+
+    /// Deletes every object not reachable from HEAD, a branch, a tag or the
+    /// index. With `dry_run` nothing is deleted and the report lists what
+    /// would be.
+    pub async fn gc(&self, dry_run: bool) -> Result<GcReport> {
+        let reachable: HashSet<String> = self.reachable_objects().await?;
+        let mut report: GcReport = GcReport::default();
+        for hash in self.object_hashes().await? {
+            if reachable.contains(&hash) {
+                continue;
+            }
+            report.bytes += self.objects.size(&hash).await?;
+            report.objects += 1;
+            if !dry_run {
+                self.objects.remove(&hash).await?;
+            }
+            report.removed.push(hash);
+        }
+        say!(
+            self,
+            "{} {} unreachable objects ({} bytes)",
+            if dry_run { "Would remove" } else { "Removed" },
+            report.objects,
+            report.bytes
+        );
+        Ok(report)
+    }
+
+    /// Commits reachable from any ref plus their trees and blobs, and the
+    /// blobs staged in the index or kept in the stash.
+    async fn reachable_objects(&self) -> Result<HashSet<String>> {
+        let mut roots: Vec<String> = vec![self.get_current_head().await?];
+        for dir in [&self.heads_path, &self.tags_path] {
+            for name in Self::list_refs(dir).await? {
+                roots.push(fs::read_to_string(dir.join(name)).await?.trim().to_string());
+            }
+        }
+        let mut reachable: HashSet<String> = self
+            .read_index()
+            .await?
+            .into_iter()
+            .filter(|entry| !entry.is_deletion())
+            .map(|entry| entry.hash)
+            .collect();
+        if let Some((merge_head, _)) = self.read_merge_state().await? {
+            roots.push(merge_head);
+        }
+        for entry in self.stash_list().await? {
+            roots.push(entry.base);
+            reachable.extend(
+                entry
+                    .index
+                    .into_iter()
+                    .chain(entry.worktree)
+                    .filter(|file| !file.is_deletion())
+                    .map(|file| file.hash),
+            );
+        }
+        for root in roots.into_iter().filter(|root| !root.is_empty()) {
+            self.collect_reachable(&root, &mut reachable).await?;
+        }
+        Ok(reachable)
+    }
+
+    /// Adds `root` and every commit, tree and blob reachable from it to
+    /// `reachable`. Commits already in the set are not walked again.
+    async fn collect_reachable(&self, root: &str, reachable: &mut HashSet<String>) -> Result<()> {
+        if reachable.contains(root) {
+            return Ok(());
+        }
+        for commit_hash in self.ancestors(root).await? {
+            if !reachable.insert(commit_hash.clone()) {
+                continue;
+            }
+            let commit: CommitData = self.load_commit(&commit_hash).await?;
+            reachable.extend(commit.files.iter().map(|file| file.hash.clone()));
+            let mut trees: Vec<String> = vec![commit.tree];
+            while let Some(tree) = trees.pop() {
+                if tree.is_empty() {
+                    continue;
+                }
+                let entries: Vec<TreeEntry> =
+                    Self::parse_object(&tree, &self.read_object(&tree).await?)?;
+                reachable.insert(tree);
+                for entry in entries {
+                    if entry.kind == EntryKind::Tree {
+                        trees.push(entry.hash);
+                    } else {
+                        reachable.insert(entry.hash);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hashes `content` as a blob and, when `write` is set, stores it in the
+    /// object store (compressed like every other object).
+    pub async fn hash_object(&self, content: &[u8], write: bool) -> Result<String> {
+        let hash: String = self.hash(ObjectType::Blob, content);
+        if write && !self.object_exists(&hash) {
+            self.write_object(&hash, ObjectType::Blob, content).await?;
+        }
+        Ok(hash)
+    }
+
+    /// Hashes of every object in the store, sorted.
+    async fn object_hashes(&self) -> Result<Vec<String>> {
+        let mut hashes: Vec<String> = self.iter_objects().collect::<Result<Vec<String>>>()?;
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    /// Yields the hash of every object in the store, in no particular order.
+    /// The store on disk reads one shard directory at a time rather than
+    /// listing everything up front, and skips anything whose name is not a
+    /// hash, such as a temporary file; objects still stored flat need
+    /// [`Sgvcs::migrate_objects`] first. The directory reads block.
+    pub fn iter_objects(&self) -> impl Iterator<Item = Result<String>> + '_ {
+        self.objects.hashes()
+    }
+
+    async fn load_commit(&self, commithash: &str) -> Result<CommitData> {
+        let commithash: String = self.resolve_revision(commithash).await?;
+        self.get_commit_data(commithash).await
+    }
+
+    /// Files recorded in a commit, or nothing for an empty hash (no commits yet).
+    async fn commit_files(&self, commithash: &str) -> Result<Vec<IndexData>> {
+        if commithash.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(self.get_commit_data(commithash.to_string()).await?.files)
+    }
+
+    async fn get_commit_data(&self, commithash: String) -> Result<CommitData> {
+        let commithash: String = self.resolve_hash(&commithash).await?;
+        let buffer: Vec<u8> = self.read_object(&commithash).await?;
+        let mut data: CommitData = Self::parse_object(&commithash, &buffer)?;
+        if !data.tree.is_empty() {
+            data.files = self.read_tree(&data.tree).await?;
+        }
+        Ok(data)
+    }
+
+    /// Parses a commit or tree object, reporting bad JSON as corruption of
+    /// that object.
+    fn parse_object<T: DeserializeOwned>(hash: &str, content: &[u8]) -> Result<T> {
+        serde_json::from_slice(content).map_err(|err| SgvcsError::CorruptObject {
+            hash: hash.to_string(),
+            reason: err.to_string(),
+        })
+    }
+
+    /// Writes one tree object per directory in `files` and returns the hash of
+    /// the root tree. Entries are sorted by name, so the same set of files
+    /// always produces the same tree hash.
+    async fn write_tree(&self, files: &[IndexData]) -> Result<String> {
+        let (root_hash, objects) = self.tree_objects(files)?;
+        for (hash, content) in objects {
+            if !self.object_exists(&hash) {
+                self.write_object(&hash, ObjectType::Tree, &content).await?;
+            }
+        }
+        Ok(root_hash)
+    }
+
+    /// The root tree hash for `files` and every tree object it is made of,
+    /// without writing anything.
+    fn tree_objects(&self, files: &[IndexData]) -> Result<(String, Vec<TreeObject>)> {
+        self.tree_objects_as(files, canonical_json)
+    }
+
+    fn tree_objects_as(
+        &self,
+        files: &[IndexData],
+        encoding: TreeEncoding,
+    ) -> Result<(String, Vec<TreeObject>)> {
+        let mut root: BTreeMap<String, TreeNode> = BTreeMap::new();
+        for file in files {
+            let components: Vec<&str> = Path::new(&file.path)
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => name.to_str(),
+                    _ => None,
+                })
+                .collect();
+            let Some((name, dirs)) = components.split_last() else {
+                continue;
+            };
+            let mut node: &mut BTreeMap<String, TreeNode> = &mut root;
+            for dir in dirs {
+                let entry: &mut TreeNode = node
+                    .entry(dir.to_string())
+                    .or_insert_with(|| TreeNode::Dir(BTreeMap::new()));
+                node = match entry {
+                    TreeNode::Dir(children) => children,
+                    TreeNode::Blob(..) => {
+                        return Err(SgvcsError::InvalidInput(format!(
+                            "{:?} is tracked as both a file and a directory",
+                            dir
+                        )))
+                    }
+                };
+            }
+            node.insert(
+                name.to_string(),
+                TreeNode::Blob(file.hash.clone(), file.mode),
+            );
+        }
+
+        let mut objects: Vec<TreeObject> = Vec::new();
+        let root_hash: String = self.build_tree(&root, encoding, &mut objects)?;
+        Ok((root_hash, objects))
+    }
+
+    fn build_tree(
+        &self,
+        node: &BTreeMap<String, TreeNode>,
+        encoding: TreeEncoding,
+        objects: &mut Vec<TreeObject>,
+    ) -> Result<String> {
+        let mut entries: Vec<TreeEntry> = Vec::new();
+        for (name, child) in node {
+            let (kind, hash, mode) = match child {
+                TreeNode::Blob(hash, mode) => (EntryKind::Blob, hash.clone(), *mode),
+                TreeNode::Dir(children) => (
+                    EntryKind::Tree,
+                    self.build_tree(children, encoding, objects)?,
+                    DEFAULT_MODE,
+                ),
+            };
+            entries.push(TreeEntry {
+                name: name.clone(),
+                kind,
+                hash,
+                mode,
+            });
+        }
+        let content: Vec<u8> = encoding(&entries)?;
+        let hash: String = self.hash(ObjectType::Tree, &content);
+        objects.push((hash.clone(), content));
+        Ok(hash)
+    }
+
+    /// Flattens a tree object back into `dir/file` paths, sorted by path.
+    async fn read_tree(&self, tree_hash: &str) -> Result<Vec<IndexData>> {
+        let mut files: Vec<IndexData> = Vec::new();
+        let mut pending: Vec<(String, String)> = vec![(String::new(), tree_hash.to_string())];
+        while let Some((prefix, hash)) = pending.pop() {
+            let entries: Vec<TreeEntry> =
+                Self::parse_object(&hash, &self.read_object(&hash).await?)?;
+            for entry in entries {
+                let path: String = format!("{}{}", prefix, entry.name);
+                match entry.kind {
+                    EntryKind::Blob => files.push(IndexData {
+                        path,
+                        hash: entry.hash,
+                        mode: entry.mode,
+                    }),
+                    EntryKind::Tree => pending.push((format!("{}/", path), entry.hash)),
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files)
+    }
+
+    /// Name of an object of type `kind`: the hash of its header and content.
+    fn hash(&self, kind: ObjectType, content: &[u8]) -> String {
+        let mut hasher: StreamHasher = self.hasher();
+        hasher.update(&kind.header(content.len() as u64));
+        hasher.update(content);
+        hasher.finish()
+    }
+
+    /// Hash of exactly `bytes`, which names an object as it is stored.
+    fn hash_stored(&self, bytes: &[u8]) -> String {
+        let mut hasher: StreamHasher = self.hasher();
+        hasher.update(bytes);
+        hasher.finish()
+    }
+
+    /// The repo's `core.autocrlf` setting; unknown values are an error.
+    async fn autocrlf(&self) -> Result<AutoCrlf> {
+        match self.get_config(AUTOCRLF_KEY).await? {
+            Some(value) => AutoCrlf::parse(value.trim()),
+            None => Ok(AutoCrlf::False),
+        }
+    }
+
+    /// Whether `content` is the blob named `hash`, including blobs written
+    /// without a header before format version 2.
+    fn blob_matches(&self, hash: &str, content: &[u8]) -> bool {
+        self.hash(ObjectType::Blob, content) == hash || self.hash_stored(content) == hash
+    }
+
+    fn hasher(&self) -> StreamHasher {
+        match self.hash_algo {
+            HashAlgo::Sha1 => StreamHasher::Sha1(Sha1::new()),
+            HashAlgo::Sha256 => StreamHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    async fn get_file_contents(&self, file_hash: String) -> Result<Vec<u8>> {
+        self.read_object(&file_hash).await
+    }
+
+    /// Moves objects stored flat in `objects/` (the layout before sharding)
+    /// into their shard directories. Returns how many objects were moved; a
+    /// store not on disk has none to move.
+    pub async fn migrate_objects(&self) -> Result<usize> {
+        let Some(dir) = self.objects.dir() else {
+            return Ok(0);
+        };
+        let layout: FsObjectStore = FsObjectStore::new(dir.to_path_buf());
+        let mut moved: usize = 0;
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name: String = entry.file_name().to_string_lossy().to_string();
+            let is_flat_object: bool = entry.file_type().await?.is_file()
+                && name.len() > 2
+                && name.chars().all(|c| c.is_ascii_hexdigit());
+            if !is_flat_object {
+                continue;
+            }
+            let target: PathBuf = layout.object_path(&name);
+            if let Some(shard) = target.parent() {
+                fs::create_dir_all(shard).await?;
+            }
+            fs::rename(entry.path(), &target).await?;
+            moved += 1;
+        }
+        if moved > 0 {
+            say!(self, "Moved {} objects into sharded directories", moved);
+        }
+        Ok(moved)
+    }
+
+    /// Objects are stored zlib-compressed behind their header; the hash is
+    /// always taken over the uncompressed bytes so content addressing does
+    /// not depend on it.
+    async fn write_object(&self, hash: &str, kind: ObjectType, content: &[u8]) -> Result<()> {
+        let mut stored: Vec<u8> = kind.header(content.len() as u64);
+        stored.extend_from_slice(content);
+        self.write_object_file(hash, &Self::compress(&stored)?)
+            .await
+    }
+
+    /// Stores object `hash` exactly as given, already compressed.
+    async fn write_object_file(&self, hash: &str, bytes: &[u8]) -> Result<()> {
+        self.objects.write(hash, bytes).await
+    }
+
+    /// Stores the `len` bytes read from `reader` as a blob and returns its
+    /// hash. Content is hashed and compressed chunk by chunk into a temporary
+    /// file that is renamed into place once the hash is known, so memory use
+    /// does not grow with the size of the content; a store not on disk gets
+    /// the compressed blob in one piece instead. The header needs the length
+    /// up front, so a reader that yields a different amount fails. A failed
+    /// write leaves no temporary file behind.
+    async fn write_object_from(&self, reader: impl AsyncRead + Unpin, len: u64) -> Result<String> {
+        let Some(dir) = self.objects.dir() else {
+            let mut compressed: Vec<u8> = Vec::new();
+            let hash: String = self
+                .compress_from(reader, len, None, &mut compressed)
+                .await?;
+            // An existing object with this hash already holds identical content
+            if !self.objects.contains(&hash) {
+                self.objects.write(&hash, &compressed).await?;
+            }
+            return Ok(hash);
+        };
+
+        let temp_path: PathBuf = dir.join(self.temp_names.next("object"));
+        let mut temp: fs::File = fs::File::create(&temp_path).await?;
+        let written: Result<String> = self
+            .compress_from(reader, len, Some(&mut temp), &mut Vec::new())
+            .await;
+        drop(temp);
+        let placed: Result<String> = match written {
+            Ok(hash) if self.objects.contains(&hash) => Ok(hash),
+            Ok(hash) => {
+                let object_path: PathBuf = FsObjectStore::new(dir.to_path_buf()).object_path(&hash);
+                Self::rename_into_place(&temp_path, &object_path)
+                    .await
+                    .map(|()| hash)
+            }
+            Err(err) => Err(err),
+        };
+        // Whatever happened, nothing should be left at the temporary path
+        if temp_path.exists() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+        placed
+    }
+
+    /// Moves a finished temporary object file to `object_path`, creating its
+    /// shard directory if needed.
+    async fn rename_into_place(temp_path: &Path, object_path: &Path) -> Result<()> {
+        if let Some(shard) = object_path.parent() {
+            fs::create_dir_all(shard).await?;
+        }
+        fs::rename(temp_path, object_path).await?;
+        Ok(())
+    }
+
+    /// Hashes and compresses the blob of `len` bytes from `reader`, writing
+    /// the compressed bytes to `file`, synced once complete, or else
+    /// appending them to `compressed`. Returns the blob's hash.
+    async fn compress_from(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+        len: u64,
+        mut file: Option<&mut fs::File>,
+        compressed: &mut Vec<u8>,
+    ) -> Result<String> {
+        let mut encoder: ZlibEncoder<Vec<u8>> =
+            ZlibEncoder::new(Vec::new(), Compression::default());
+        let mut hasher: StreamHasher = self.hasher();
+        let header: Vec<u8> = ObjectType::Blob.header(len);
+        hasher.update(&header);
+        encoder.write_all(&header)?;
+        let mut total: u64 = 0;
+        let mut buffer: Vec<u8> = vec![0; CHUNK_SIZE];
+        loop {
+            let read: usize = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            total += read as u64;
+            hasher.update(&buffer[..read]);
+            encoder.write_all(&buffer[..read])?;
+            match &mut file {
+                Some(file) => file.write_all(encoder.get_ref()).await?,
+                None => compressed.extend_from_slice(encoder.get_ref()),
+            }
+            encoder.get_mut().clear();
+        }
+        if total != len {
+            return Err(SgvcsError::InvalidInput(format!(
+                "Expected {} bytes of content but read {}; was the file changed while it was added?",
+                len, total
+            )));
+        }
+        let rest: Vec<u8> = encoder.finish()?;
+        match file {
+            Some(file) => {
+                file.write_all(&rest).await?;
+                file.sync_all().await?;
+            }
+            None => compressed.extend_from_slice(&rest),
+        }
+        Ok(hasher.finish())
+    }
+
+    /// Content of an object without its header.
+    async fn read_object(&self, hash: &str) -> Result<Vec<u8>> {
+        let mut stored: Vec<u8> = self.read_stored_object(hash).await?;
+        let (_, offset) = ObjectType::split_header(&stored);
+        stored.drain(..offset);
+        Ok(stored)
+    }
+
+    /// An object as stored, header included, after decompression.
+    async fn read_stored_object(&self, hash: &str) -> Result<Vec<u8>> {
+        self.decompress(hash, self.read_object_file(hash).await?)
+    }
+
+    /// Object `hash` as it is stored, still compressed.
+    async fn read_object_file(&self, hash: &str) -> Result<Vec<u8>> {
+        self.objects.read(hash).await
+    }
+
+    fn compress(content: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder: ZlibEncoder<Vec<u8>> =
+            ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content)?;
+        Ok(encoder.finish()?)
+    }
+
+    /// Inflates the stored bytes of object `hash`. Bytes that are not zlib
+    /// data are only taken as an object stored verbatim when they start with
+    /// an object header, or when they hash to `hash` as objects written
+    /// before compression and headers do; anything else is a damaged object.
+    fn decompress(&self, hash: &str, stored: Vec<u8>) -> Result<Vec<u8>> {
+        let mut decoder: ZlibDecoder<&[u8]> = ZlibDecoder::new(stored.as_slice());
+        let mut content: Vec<u8> = Vec::new();
+        match decoder.read_to_end(&mut content) {
+            Ok(_) => Ok(content),
+            Err(_)
+                if ObjectType::parse_header(&stored).is_some()
+                    || self.hash_stored(&stored) == hash =>
+            {
+                Ok(stored)
+            }
+            Err(err) => Err(SgvcsError::CorruptObject {
+                hash: hash.to_string(),
+                reason: format!("cannot be decompressed: {}", err),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryObjectStore;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// A fresh repo in a temporary directory with an author configured and
+    /// its output discarded. The directory goes away with the `TempDir`.
+    async fn test_repo() -> (TempDir, Sgvcs) {
+        let dir: TempDir = tempfile::tempdir().unwrap();
+        let root: PathBuf = fs::canonicalize(dir.path()).await.unwrap();
+        let sgvcs: Sgvcs = Sgvcs::at(root);
+        sgvcs.set_output(std::io::sink());
+        sgvcs.init().await.unwrap();
+        sgvcs.set_config("user.name", "Tester").await.unwrap();
+        sgvcs
+            .set_config("user.email", "tester@example.com")
+            .await
+            .unwrap();
+        (dir, sgvcs)
+    }
+
+    /// Writes `contents` to `name` in the working tree and returns its path.
+    async fn write_file(sgvcs: &Sgvcs, name: &str, contents: &str) -> PathBuf {
+        let path: PathBuf = sgvcs.root_path.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.unwrap();
+        }
+        fs::write(&path, contents).await.unwrap();
+        path
+    }
+
+    /// Writes, stages and commits one file, returning the commit hash.
+    async fn commit_file(sgvcs: &mut Sgvcs, name: &str, contents: &str, message: &str) -> String {
+        let path: PathBuf = write_file(sgvcs, name, contents).await;
+        sgvcs.add_file(&path).await.unwrap();
+        sgvcs.commit(message.to_string(), false).await.unwrap()
+    }
+
+    async fn read_file(sgvcs: &Sgvcs, name: &str) -> String {
+        fs::read_to_string(sgvcs.root_path.join(name))
+            .await
+            .unwrap()
+    }
+
+    /// Collects everything the repo prints from now on.
+    fn capture_output(sgvcs: &Sgvcs) -> Arc<Mutex<Vec<u8>>> {
+        struct Capture(Arc<Mutex<Vec<u8>>>);
+        impl Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let buffer: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(Vec::new()));
+        sgvcs.set_output(Capture(buffer.clone()));
+        buffer
+    }
+
+    fn captured(buffer: &Arc<Mutex<Vec<u8>>>) -> String {
+        String::from_utf8(buffer.lock().unwrap().clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn relative_path_refuses_paths_outside_the_repo() {
+        let (_dir, sgvcs) = test_repo().await;
+        let root: &Path = &sgvcs.root_path;
+        assert_eq!(
+            sgvcs.relative_path(&root.join("src/./lib.rs")).unwrap(),
+            PathBuf::from("src/lib.rs")
+        );
+        assert_eq!(
+            sgvcs.relative_path(&root.join("src/../a.txt")).unwrap(),
+            PathBuf::from("a.txt")
+        );
+        assert!(matches!(
+            sgvcs.relative_path(Path::new("/tmp/outside.txt")),
+            Err(SgvcsError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            sgvcs.relative_path(&root.join("src/../../outside.txt")),
+            Err(SgvcsError::InvalidInput(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn add_refuses_files_outside_the_repo() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let outside: TempDir = tempfile::tempdir().unwrap();
+        let path: PathBuf = outside.path().join("outside.txt");
+        fs::write(&path, "outside").await.unwrap();
+        assert!(matches!(
+            sgvcs.add_file(&path).await,
+            Err(SgvcsError::InvalidInput(_))
+        ));
+        assert!(sgvcs.read_index().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rm_never_deletes_outside_the_repo() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let outside: TempDir = tempfile::tempdir().unwrap();
+        let path: PathBuf = outside.path().join("outside.txt");
+        fs::write(&path, "outside").await.unwrap();
+        assert!(sgvcs.rm(&path, false).await.is_err());
+        assert!(path.exists());
+        assert!(sgvcs.working_path("/tmp/outside.txt").is_err());
+        assert!(sgvcs.working_path("../outside.txt").is_err());
+        assert_eq!(
+            sgvcs.working_path("src/a.txt").unwrap(),
+            sgvcs.root_path.join("src/a.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn rm_deletes_and_unstages_a_tracked_file() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let path: PathBuf = write_file(&sgvcs, "a.txt", "a").await;
+        sgvcs.add_file(&path).await.unwrap();
+        commit_file(&mut sgvcs, "b.txt", "b", "add a and b").await;
+        sgvcs.rm(&path, false).await.unwrap();
+        assert!(!path.exists());
+        let index: Vec<IndexData> = sgvcs.read_index().await.unwrap();
+        assert!(index.len() == 1 && index[0].is_deletion());
+
+        let commit: String = sgvcs.commit("remove a".to_string(), false).await.unwrap();
+        assert_eq!(
+            committed_files(&sgvcs, &commit).await,
+            vec![("b.txt".to_string(), "b".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn merge_refuses_to_overwrite_local_changes() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "a.txt", "one", "one").await;
+        sgvcs.branch("feature").await.unwrap();
+        sgvcs.checkout("feature", false).await.unwrap();
+        commit_file(&mut sgvcs, "a.txt", "two", "two").await;
+        sgvcs.checkout("main", false).await.unwrap();
+
+        // A fast-forward
+        write_file(&sgvcs, "a.txt", "LOCAL WORK").await;
+        assert!(matches!(
+            sgvcs.merge("feature").await,
+            Err(SgvcsError::InvalidInput(_))
+        ));
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "LOCAL WORK");
+
+        // A three-way merge
+        write_file(&sgvcs, "a.txt", "one").await;
+        commit_file(&mut sgvcs, "b.txt", "b", "b").await;
+        write_file(&sgvcs, "a.txt", "LOCAL WORK").await;
+        assert!(sgvcs.merge("feature").await.is_err());
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "LOCAL WORK");
+        assert!(sgvcs.merge_head().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn bare_repos_refuse_commands_that_write_the_working_tree() {
+        let dir: TempDir = tempfile::tempdir().unwrap();
+        let mut bare: Sgvcs = Sgvcs::init_bare(&dir.path().join("bare.sgvcs"))
+            .await
+            .unwrap();
+        bare.set_output(std::io::sink());
+        let path: PathBuf = bare.root_path.join("a.txt");
+        let refused = |result: Result<()>| matches!(result, Err(SgvcsError::InvalidInput(message)) if message.contains("bare"));
+        assert!(refused(bare.reset("main", ResetMode::Hard).await));
+        assert!(refused(bare.merge("main").await.map(|_| ())));
+        assert!(refused(bare.merge_abort().await));
+        assert!(refused(bare.restore_file(&path, "main").await));
+        assert!(refused(bare.rm(&path, false).await));
+        assert!(refused(bare.stage_deletion(&path).await));
+        assert!(refused(bare.bisect_start("main", "main").await.map(|_| ())));
+        assert!(refused(bare.bisect_good().await.map(|_| ())));
+        assert!(refused(bare.bisect_bad().await.map(|_| ())));
+        assert!(refused(bare.bisect_reset().await));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stash_push_keeps_changes_when_the_stash_cannot_be_written() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "a.txt", "one", "one").await;
+        write_file(&sgvcs, "a.txt", "LOCAL WORK").await;
+        // A directory where the stash file belongs makes writing it fail
+        fs::create_dir(&sgvcs.stash_path).await.unwrap();
+        assert!(sgvcs.stash_push().await.is_err());
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "LOCAL WORK");
+
+        fs::remove_dir(&sgvcs.stash_path).await.unwrap();
+        sgvcs.stash_push().await.unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "one");
+        assert_eq!(sgvcs.stash_list().await.unwrap().len(), 1);
+        sgvcs.stash_pop().await.unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "LOCAL WORK");
+        assert!(sgvcs.stash_list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn clone_leaves_working_tree_state_behind() {
+        let (dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "a.txt", "one", "one").await;
+        for name in [
+            MERGE_HEAD_FILE,
+            MERGE_CONFLICTS_FILE,
+            BISECT_FILE,
+            INDEX_LOCK_FILE,
+            "stash",
+            "tmp_index_1",
+        ] {
+            fs::write(sgvcs.repo_path.join(name), "stale")
+                .await
+                .unwrap();
+        }
+        let dest: PathBuf = dir.path().join("clone");
+        let mut clone: Sgvcs = Sgvcs::clone(&sgvcs.root_path, &dest).await.unwrap();
+        clone.set_output(std::io::sink());
+        for name in [
+            MERGE_HEAD_FILE,
+            MERGE_CONFLICTS_FILE,
+            BISECT_FILE,
+            INDEX_LOCK_FILE,
+            "stash",
+            "tmp_index_1",
+        ] {
+            assert!(!clone.repo_path.join(name).exists(), "{} was copied", name);
+        }
+        assert!(!clone.reflog_path.exists());
+        assert_eq!(read_file(&clone, "a.txt").await, "one");
+
+        // The clone is not stuck in the source's merge or lock
+        let hash: String = commit_file(&mut clone, "b.txt", "b", "b").await;
+        assert_eq!(
+            clone
+                .get_commit_data(hash)
+                .await
+                .unwrap()
+                .parent_hashes()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn ignore_warnings_go_to_the_output_sink() {
+        let (_dir, sgvcs) = test_repo().await;
+        write_file(&sgvcs, ".sgvcsignore", "[\n").await;
+        let output: Arc<Mutex<Vec<u8>>> = capture_output(&sgvcs);
+        sgvcs.load_ignore().await.unwrap();
+        assert!(captured(&output).starts_with("Skipping invalid ignore pattern \"[\""));
+    }
+
+    /// Yields `chunk` once and then fails, like a file that becomes
+    /// unreadable halfway through.
+    struct FailingReader {
+        chunk: Option<Vec<u8>>,
+    }
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(match self.chunk.take() {
+                Some(chunk) => {
+                    buf.put_slice(&chunk);
+                    Ok(())
+                }
+                None => Err(std::io::Error::other("disk went away")),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_write_cleans_up_after_a_failed_read() {
+        let (_dir, sgvcs) = test_repo().await;
+        let reader: FailingReader = FailingReader {
+            chunk: Some(b"half".to_vec()),
+        };
+        assert!(sgvcs.write_object_from(reader, 8).await.is_err());
+        let mut entries = fs::read_dir(sgvcs.objects.dir().unwrap()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert!(!entry.file_name().to_string_lossy().starts_with("tmp_"));
+        }
+        assert_eq!(sgvcs.iter_objects().count(), 0);
+
+        let hash: String = sgvcs.write_object_from(&b"complete"[..], 8).await.unwrap();
+        assert_eq!(sgvcs.read_object(&hash).await.unwrap(), b"complete");
+        assert!(matches!(
+            sgvcs.write_object_from(&b"short"[..], 8).await,
+            Err(SgvcsError::InvalidInput(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_file_several_chunks_long_streams_into_one_blob() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| (i % 251) as u8).collect();
+        let hash: String = staged_hash(&mut sgvcs, "big.bin", &contents).await;
+        assert_eq!(hash, sgvcs.hash_object(&contents, false).await.unwrap());
+        assert_eq!(sgvcs.read_object(&hash).await.unwrap(), contents);
+        assert!(sgvcs.fsck().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_use_their_own_temporary_files() {
+        let (_dir, sgvcs) = test_repo().await;
+        let one: Vec<u8> = vec![1; CHUNK_SIZE * 2];
+        let two: Vec<u8> = vec![2; CHUNK_SIZE * 2];
+        let (first, second) = tokio::join!(
+            sgvcs.write_object_from(&one[..], one.len() as u64),
+            sgvcs.write_object_from(&two[..], two.len() as u64)
+        );
+        assert_eq!(sgvcs.read_object(&first.unwrap()).await.unwrap(), one);
+        assert_eq!(sgvcs.read_object(&second.unwrap()).await.unwrap(), two);
+
+        let notes: PathBuf = sgvcs.repo_path.join("notes");
+        let (first, second) = tokio::join!(
+            sgvcs.write_atomic(&notes, b"one"),
+            sgvcs.write_atomic(&notes, b"two")
+        );
+        first.unwrap();
+        second.unwrap();
+        let written: String = fs::read_to_string(&notes).await.unwrap();
+        assert!(written == "one" || written == "two");
+    }
+
+    #[tokio::test]
+    async fn trees_and_commits_use_the_canonical_encoding() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        write_file(&sgvcs, "dir/b.txt", "b").await;
+        sgvcs
+            .add_file(&sgvcs.root_path.join("dir/b.txt"))
+            .await
+            .unwrap();
+        let hash: String = commit_file(&mut sgvcs, "a.txt", "a", "two files").await;
+        let commit: CommitData = sgvcs.get_commit_data(hash.clone()).await.unwrap();
+
+        // Stored bytes are the canonical encoding, which round-trips exactly
+        let stored: Vec<u8> = sgvcs.read_object(&hash).await.unwrap();
+        assert_eq!(stored, commit.canonical_bytes().unwrap());
+        let reparsed: CommitData = serde_json::from_slice(&stored).unwrap();
+        assert_eq!(reparsed.canonical_bytes().unwrap(), stored);
+        assert!(sgvcs.verify_commit(&hash).await.unwrap());
+
+        let tree: Vec<u8> = sgvcs.read_object(&commit.tree).await.unwrap();
+        assert!(!tree.contains(&b'\n'));
+        let entries: Vec<TreeEntry> = serde_json::from_slice(&tree).unwrap();
+        assert_eq!(canonical_json(&entries).unwrap(), tree);
+        assert_eq!(sgvcs.tree_objects(&commit.files).unwrap().0, commit.tree);
+        assert!(sgvcs.fsck().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fsck_accepts_trees_written_before_the_canonical_encoding() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let hash: String = commit_file(&mut sgvcs, "a.txt", "a", "one").await;
+        let commit: CommitData = sgvcs.get_commit_data(hash).await.unwrap();
+        let (tree, objects) = sgvcs.tree_objects_as(&commit.files, pretty_tree).unwrap();
+        assert_ne!(tree, commit.tree);
+        for (hash, content) in objects {
+            sgvcs
+                .write_object(&hash, ObjectType::Tree, &content)
+                .await
+                .unwrap();
+        }
+        let legacy: CommitData = CommitData { tree, ..commit };
+        let bytes: Vec<u8> = serde_json::to_vec_pretty(&legacy).unwrap();
+        let legacy_hash: String = sgvcs.hash(ObjectType::Commit, &bytes);
+        sgvcs
+            .write_object(&legacy_hash, ObjectType::Commit, &bytes)
+            .await
+            .unwrap();
+        assert!(sgvcs.fsck().await.unwrap().is_empty());
+        assert!(sgvcs.verify_commit(&legacy_hash).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn read_commands_on_a_fresh_repo() {
+        let (_dir, sgvcs) = test_repo().await;
+        let path: PathBuf = sgvcs.root_path.join("a.txt");
+        let no_commits = |result: Result<String>| matches!(result, Err(SgvcsError::NoCommits));
+
+        // History reads come back empty
+        sgvcs.log(None, false).await.unwrap();
+        assert!(sgvcs.log_entries(None).await.unwrap().is_empty());
+        assert_eq!(sgvcs.log_json().await.unwrap(), "[]");
+        assert_eq!(sgvcs.log_graph().await.unwrap(), "");
+        assert!(sgvcs.file_history(&path).await.unwrap().is_empty());
+        assert!(sgvcs.find_commits("x", false).await.unwrap().is_empty());
+        assert!(sgvcs.reflog().await.unwrap().is_empty());
+        assert!(sgvcs.head_commit().await.unwrap().is_none());
+        assert!(sgvcs.list_files("").await.unwrap().is_empty());
+        assert!(sgvcs.list_branches().await.unwrap().is_empty());
+        assert!(sgvcs.list_tags().await.unwrap().is_empty());
+        assert!(sgvcs.stash_list().await.unwrap().is_empty());
+
+        // So do the working tree and store
+        sgvcs.status().await.unwrap();
+        assert_eq!(
+            sgvcs.status_report().await.unwrap(),
+            StatusReport::default()
+        );
+        assert!(sgvcs.status_json().await.is_ok());
+        assert!(sgvcs.is_clean().await.unwrap());
+        assert_eq!(sgvcs.staged_diff().await.unwrap(), "");
+        assert_eq!(sgvcs.worktree_diff().await.unwrap(), "");
+        assert!(sgvcs.fsck().await.unwrap().is_empty());
+        assert_eq!(sgvcs.count_objects().await.unwrap().objects, 0);
+        sgvcs.verify_index().await.unwrap();
+        assert!(!sgvcs.is_detached().await);
+        assert!(sgvcs.merge_head().await.unwrap().is_none());
+
+        // Anything naming a commit says there is none yet
+        assert!(no_commits(sgvcs.resolve_revision("HEAD").await));
+        assert!(no_commits(sgvcs.resolve_revision(DEFAULT_BRANCH).await));
+        assert!(no_commits(sgvcs.diff_commit("HEAD").await));
+        assert!(no_commits(sgvcs.diff_commits("HEAD", DEFAULT_BRANCH).await));
+        assert!(no_commits(sgvcs.show("HEAD").await));
+        assert!(no_commits(sgvcs.describe("HEAD").await));
+        assert!(matches!(
+            sgvcs.list_files(DEFAULT_BRANCH).await,
+            Err(SgvcsError::NoCommits)
+        ));
+        assert!(matches!(
+            sgvcs.blame(&path).await,
+            Err(SgvcsError::NoCommits)
+        ));
+        assert!(matches!(
+            sgvcs
+                .archive("HEAD", &sgvcs.root_path.join("out.tar"))
+                .await,
+            Err(SgvcsError::NoCommits)
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_memory_object_store_keeps_objects_off_disk() {
+        let dir: TempDir = tempfile::tempdir().unwrap();
+        let mut sgvcs: Sgvcs = Sgvcs::with_object_store(dir.path(), MemoryObjectStore::new())
+            .await
+            .unwrap();
+        sgvcs.set_output(std::io::sink());
+        sgvcs.set_config("user.name", "Tester").await.unwrap();
+        sgvcs
+            .set_config("user.email", "tester@example.com")
+            .await
+            .unwrap();
+        let first: String = commit_file(&mut sgvcs, "a.txt", "one\n", "first").await;
+        sgvcs.branch("old").await.unwrap();
+        let second: String = commit_file(&mut sgvcs, "a.txt", "two\n", "second").await;
+
+        assert!(!sgvcs.repo_path.join("objects").exists());
+        assert!(sgvcs.objects.contains(&second));
+        assert_eq!(sgvcs.resolve_hash(&first[..8]).await.unwrap(), first);
+        let entries: Vec<LogEntry> = sgvcs.log_entries(None).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(sgvcs.fsck().await.unwrap().is_empty());
+
+        sgvcs.checkout("old", false).await.unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "one\n");
+    }
+
+    #[tokio::test]
+    async fn re_adding_a_file_replaces_its_index_entry() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let path: PathBuf = write_file(&sgvcs, "a.txt", "one\n").await;
+        sgvcs.add_file(&path).await.unwrap();
+        write_file(&sgvcs, "a.txt", "two\n").await;
+        sgvcs.add_file(&path).await.unwrap();
+
+        let index: Vec<IndexData> = sgvcs.read_index().await.unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[0].path, "a.txt");
+        assert_eq!(
+            index[0].hash,
+            sgvcs.hash_object(b"two\n", false).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn objects_are_stored_compressed() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let contents: String = "the same line over and over\n".repeat(4096);
+        let path: PathBuf = write_file(&sgvcs, "big.txt", &contents).await;
+        sgvcs.add_file(&path).await.unwrap();
+
+        let hash: String = sgvcs.read_index().await.unwrap()[0].hash.clone();
+        assert_eq!(
+            hash,
+            sgvcs.hash_object(contents.as_bytes(), false).await.unwrap()
+        );
+        let stored: u64 = sgvcs.objects.size(&hash).await.unwrap();
+        assert!(stored < contents.len() as u64 / 10);
+        assert_eq!(
+            sgvcs.get_file_contents(hash).await.unwrap(),
+            contents.as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn checkout_restores_files_from_a_commit() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "one\n", "first").await;
+        commit_file(&mut sgvcs, "b.txt", "b\n", "add b").await;
+        write_file(&sgvcs, "a.txt", "edited\n").await;
+        write_file(&sgvcs, "untracked.txt", "keep me\n").await;
+
+        assert!(sgvcs.checkout(&first, false).await.is_err());
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "edited\n");
+
+        sgvcs.checkout(&first, true).await.unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "one\n");
+        assert!(!sgvcs.root_path.join("b.txt").exists());
+        assert_eq!(read_file(&sgvcs, "untracked.txt").await, "keep me\n");
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), first);
+        assert!(sgvcs.is_detached().await);
+
+        sgvcs.checkout(DEFAULT_BRANCH, false).await.unwrap();
+        assert_eq!(read_file(&sgvcs, "b.txt").await, "b\n");
+        assert!(!sgvcs.is_detached().await);
+    }
+
+    #[tokio::test]
+    async fn diff_commit_shows_only_what_the_commit_changed() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "same.txt", "unchanged\n", "first").await;
+        commit_file(&mut sgvcs, "edit.txt", "old\n", "second").await;
+        write_file(&sgvcs, "edit.txt", "new\n").await;
+        let added: PathBuf = write_file(&sgvcs, "added.txt", "x\ny\n").await;
+        sgvcs
+            .add_file(&sgvcs.root_path.join("edit.txt"))
+            .await
+            .unwrap();
+        sgvcs.add_file(&added).await.unwrap();
+        let hash: String = sgvcs.commit("third".to_string(), false).await.unwrap();
+
+        let diff: String = sgvcs.diff_commit(&hash).await.unwrap();
+        assert!(!diff.contains("same.txt"));
+        assert!(diff.contains("--- a/edit.txt\n+++ b/edit.txt\n@@ -1,1 +1,1 @@\n-old\n+new\n"));
+        assert!(diff.contains("--- /dev/null\n+++ b/added.txt\n@@ -0,0 +1,2 @@\n+x\n+y\n"));
+    }
+
+    #[tokio::test]
+    async fn committing_nothing_new_fails_unless_allowed() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "one\n", "first").await;
+        assert!(matches!(
+            sgvcs.commit("again".to_string(), false).await,
+            Err(SgvcsError::NothingToCommit)
+        ));
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), first);
+
+        let empty: String = sgvcs.commit("empty".to_string(), true).await.unwrap();
+        assert_eq!(sgvcs.load_commit(&empty).await.unwrap().parent, first);
+    }
+
+    #[tokio::test]
+    async fn staging_existing_content_leaves_its_object_alone() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let path: PathBuf = write_file(&sgvcs, "a.txt", "same\n").await;
+        sgvcs.add_file(&path).await.unwrap();
+        let hash: String = sgvcs.read_index().await.unwrap()[0].hash.clone();
+        let object_path: PathBuf =
+            FsObjectStore::new(sgvcs.objects.dir().unwrap().to_path_buf()).object_path(&hash);
+        let stored: Vec<u8> = fs::read(&object_path).await.unwrap();
+        // Backdate the object so any rewrite would show in its mtime
+        let old: std::time::SystemTime =
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&object_path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        sgvcs.add_file(&path).await.unwrap();
+        let copy: PathBuf = write_file(&sgvcs, "copy.txt", "same\n").await;
+        sgvcs.add_file(&copy).await.unwrap();
+
+        assert_eq!(fs::read(&object_path).await.unwrap(), stored);
+        assert_eq!(
+            fs::metadata(&object_path)
+                .await
+                .unwrap()
+                .modified()
+                .unwrap(),
+            old
+        );
+        assert_eq!(sgvcs.object_hashes().await.unwrap(), vec![hash]);
+    }
+
+    #[tokio::test]
+    async fn unstage_one_path_or_everything() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let a: PathBuf = write_file(&sgvcs, "a.txt", "a\n").await;
+        let b: PathBuf = write_file(&sgvcs, "b.txt", "b\n").await;
+        sgvcs.add_file(&a).await.unwrap();
+        sgvcs.add_file(&b).await.unwrap();
+
+        sgvcs.unstage(Some(&a)).await.unwrap();
+        let index: Vec<IndexData> = sgvcs.read_index().await.unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[0].path, "b.txt");
+        assert!(matches!(
+            sgvcs.unstage(Some(&a)).await,
+            Err(SgvcsError::NotFound(_))
+        ));
+
+        sgvcs.unstage(None).await.unwrap();
+        assert!(sgvcs.read_index().await.unwrap().is_empty());
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "a\n");
+        assert_eq!(read_file(&sgvcs, "b.txt").await, "b\n");
+    }
+
+    #[tokio::test]
+    async fn binary_files_commit_and_diff_without_panicking() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let path: PathBuf = sgvcs.root_path.join("blob.bin");
+        fs::write(&path, b"\x89PNG\0\xff\xfe").await.unwrap();
+        sgvcs.add_file(&path).await.unwrap();
+        sgvcs.commit("first".to_string(), false).await.unwrap();
+        fs::write(&path, b"\x89PNG\0\x00\xfe").await.unwrap();
+        sgvcs.add_file(&path).await.unwrap();
+        let second: String = sgvcs.commit("second".to_string(), false).await.unwrap();
+
+        let diff: String = sgvcs.diff_commit(&second).await.unwrap();
+        assert!(diff.contains("Binary files differ"));
+        let blob: String = sgvcs.head_files().await.unwrap()[0].hash.clone();
+        assert_eq!(
+            sgvcs.get_file_contents(blob).await.unwrap(),
+            b"\x89PNG\0\x00\xfe"
+        );
+    }
+
+    #[tokio::test]
+    async fn commit_with_time_gives_reproducible_hashes() {
+        async fn commit_at(time: DateTime<Utc>) -> (TempDir, Sgvcs, String) {
+            let (dir, mut sgvcs) = test_repo().await;
+            let path: PathBuf = write_file(&sgvcs, "a.txt", "one\n").await;
+            sgvcs.add_file(&path).await.unwrap();
+            let hash: String = sgvcs
+                .commit_with_time("first".to_string(), time, false)
+                .await
+                .unwrap();
+            (dir, sgvcs, hash)
+        }
+        let time: DateTime<Utc> = DateTime::parse_from_rfc3339("2020-01-02T03:04:05Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let (_a, sgvcs, first) = commit_at(time).await;
+        let (_b, _, again) = commit_at(time).await;
+        let (_c, _, later) = commit_at(time + chrono::Duration::seconds(1)).await;
+
+        assert_eq!(first, again);
+        assert_ne!(first, later);
+        assert_eq!(sgvcs.load_commit(&first).await.unwrap().time(), Some(time));
+    }
+
+    #[tokio::test]
+    async fn commit_returns_the_new_commit_hash() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "one\n", "first").await;
+        let second: String = commit_file(&mut sgvcs, "a.txt", "two\n", "second").await;
+
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), second);
+        assert_eq!(sgvcs.branch_tip(DEFAULT_BRANCH).await.unwrap(), second);
+        assert_eq!(sgvcs.load_commit(&second).await.unwrap().parent, first);
+        sgvcs.tag("v1", Some(&first)).await.unwrap();
+        assert_eq!(sgvcs.resolve_revision("v1").await.unwrap(), first);
+    }
+
+    #[tokio::test]
+    async fn restore_file_brings_back_a_deleted_file() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        let head: String = commit_file(&mut sgvcs, "b.txt", "b\n", "second").await;
+        let a: PathBuf = sgvcs.root_path.join("a.txt");
+        fs::remove_file(&a).await.unwrap();
+        write_file(&sgvcs, "b.txt", "edited\n").await;
+
+        sgvcs.restore_file(&a, "HEAD").await.unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "a\n");
+        assert_eq!(read_file(&sgvcs, "b.txt").await, "edited\n");
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), head);
+
+        let missing: PathBuf = sgvcs.root_path.join("missing.txt");
+        assert!(matches!(
+            sgvcs.restore_file(&missing, "HEAD").await,
+            Err(SgvcsError::NotFound(_))
+        ));
+    }
+
+    /// Two commits on `main`, then an edit to `a.txt` and a staged `c.txt`.
+    /// Returns the first commit.
+    async fn reset_fixture(sgvcs: &mut Sgvcs) -> String {
+        let first: String = commit_file(sgvcs, "a.txt", "one\n", "first").await;
+        let b: PathBuf = write_file(sgvcs, "b.txt", "b\n").await;
+        sgvcs.add_file(&b).await.unwrap();
+        commit_file(sgvcs, "a.txt", "two\n", "second").await;
+        let staged: PathBuf = write_file(sgvcs, "c.txt", "c\n").await;
+        sgvcs.add_file(&staged).await.unwrap();
+        write_file(sgvcs, "a.txt", "edited\n").await;
+        first
+    }
+
+    #[tokio::test]
+    async fn soft_reset_only_moves_head() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = reset_fixture(&mut sgvcs).await;
+        sgvcs.reset(&first, ResetMode::Soft).await.unwrap();
+
+        assert_eq!(sgvcs.branch_tip(DEFAULT_BRANCH).await.unwrap(), first);
+        assert_eq!(sgvcs.read_index().await.unwrap().len(), 1);
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "edited\n");
+    }
+
+    #[tokio::test]
+    async fn mixed_reset_clears_the_index_but_keeps_files() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = reset_fixture(&mut sgvcs).await;
+        sgvcs.reset(&first, ResetMode::Mixed).await.unwrap();
+
+        assert_eq!(sgvcs.branch_tip(DEFAULT_BRANCH).await.unwrap(), first);
+        assert!(sgvcs.read_index().await.unwrap().is_empty());
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "edited\n");
+        assert_eq!(read_file(&sgvcs, "c.txt").await, "c\n");
+    }
+
+    #[tokio::test]
+    async fn hard_reset_rewrites_the_working_tree() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = reset_fixture(&mut sgvcs).await;
+        write_file(&sgvcs, "untracked.txt", "u\n").await;
+        sgvcs.reset(&first, ResetMode::Hard).await.unwrap();
+
+        assert_eq!(sgvcs.branch_tip(DEFAULT_BRANCH).await.unwrap(), first);
+        assert!(!sgvcs.is_detached().await);
+        assert!(sgvcs.read_index().await.unwrap().is_empty());
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "one\n");
+        assert!(!sgvcs.root_path.join("b.txt").exists());
+        assert!(!sgvcs.root_path.join("c.txt").exists());
+        assert_eq!(read_file(&sgvcs, "untracked.txt").await, "u\n");
+        assert!(sgvcs.is_clean().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn history_walks_stop_on_a_parent_cycle() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let root: String = commit_file(&mut sgvcs, "a.txt", "one\n", "first").await;
+        // Content addressing cannot produce a cycle, so store two commits
+        // under made-up names that point at each other
+        let p: String = "1".repeat(40);
+        let q: String = "2".repeat(40);
+        for (name, parent) in [(&p, &q), (&q, &p)] {
+            let mut commit: CommitData = sgvcs.load_commit(&root).await.unwrap();
+            commit.parent = parent.clone();
+            let bytes: Vec<u8> = commit.canonical_bytes().unwrap();
+            sgvcs
+                .write_object(name, ObjectType::Commit, &bytes)
+                .await
+                .unwrap();
+        }
+        fs::write(sgvcs.heads_path.join(DEFAULT_BRANCH), &p)
+            .await
+            .unwrap();
+
+        let mut history: History = sgvcs.history(&p);
+        assert_eq!(history.next().await.unwrap().unwrap().0, p);
+        assert_eq!(history.next().await.unwrap().unwrap().0, q);
+        assert!(matches!(
+            history.next().await,
+            Some(Err(SgvcsError::CorruptObject { .. }))
+        ));
+        assert!(history.next().await.is_none());
+
+        assert!(sgvcs.log_entries(None).await.is_err());
+        // Whatever they report, these walks have to return
+        sgvcs.merge_base(&p, &root).await.ok();
+        assert!(!sgvcs.is_ancestor(&root, &p).await.unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn hash_object_only_stores_when_asked() {
+        let (_dir, sgvcs) = test_repo().await;
+        // Blobs hash like git's, so this is `git hash-object` of "hello\n"
+        let expected: &str = "ce013625030ba8dba906f756967f9e9ca394464a";
+        assert_eq!(
+            sgvcs.hash_object(b"hello\n", false).await.unwrap(),
+            expected
+        );
+        assert!(!sgvcs.object_exists(expected));
+
+        assert_eq!(sgvcs.hash_object(b"hello\n", true).await.unwrap(), expected);
+        assert!(sgvcs.object_exists(expected));
+        assert_eq!(sgvcs.cat_file(expected).await.unwrap(), b"hello\n");
+        assert_eq!(
+            sgvcs.cat_file_type(expected).await.unwrap(),
+            ObjectType::Blob
+        );
+        let stored: Vec<u8> = sgvcs.read_object_file(expected).await.unwrap();
+        assert_eq!(
+            sgvcs.decompress(expected, stored).unwrap(),
+            b"blob 6\0hello\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn the_executable_bit_survives_a_checkout() {
+        use std::os::unix::fs::PermissionsExt;
+        let (_dir, mut sgvcs) = test_repo().await;
+        let script: PathBuf = write_file(&sgvcs, "run.sh", "#!/bin/sh\n").await;
+        fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))
+            .await
+            .unwrap();
+        sgvcs.add_file(&script).await.unwrap();
+        let first: String = sgvcs.commit("script".to_string(), false).await.unwrap();
+        assert_eq!(sgvcs.head_files().await.unwrap()[0].mode, EXECUTABLE_MODE);
+
+        fs::remove_file(&script).await.unwrap();
+        sgvcs.checkout(&first, true).await.unwrap();
+        let mode: u32 = fs::metadata(&script).await.unwrap().permissions().mode();
+        assert_eq!(mode & 0o111, 0o111);
+
+        fs::set_permissions(&script, std::fs::Permissions::from_mode(0o644))
+            .await
+            .unwrap();
+        sgvcs.restore_file(&script, &first).await.unwrap();
+        let mode: u32 = fs::metadata(&script).await.unwrap().permissions().mode();
+        assert_eq!(mode & 0o111, 0o111);
+    }
+
+    #[tokio::test]
+    async fn failures_come_back_as_distinct_errors() {
+        let empty: TempDir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            Sgvcs::open_at(empty.path()).await,
+            Err(SgvcsError::NotARepo(_))
+        ));
+
+        let (_dir, sgvcs) = test_repo().await;
+        let missing: String = "f".repeat(40);
+        assert!(matches!(
+            sgvcs.cat_file(&missing).await,
+            Err(SgvcsError::ObjectNotFound(_))
+        ));
+
+        let first: String = format!("abcd{}", "1".repeat(36));
+        let second: String = format!("abcd{}", "2".repeat(36));
+        for name in [&first, &second] {
+            sgvcs
+                .write_object(name, ObjectType::Commit, b"not json")
+                .await
+                .unwrap();
+        }
+        match sgvcs.resolve_hash("abcd").await {
+            Err(SgvcsError::Ambiguous { prefix, matches }) => {
+                assert_eq!(prefix, "abcd");
+                assert_eq!(matches, vec![first.clone(), second]);
+            }
+            other => panic!("expected an ambiguous hash, got {:?}", other),
+        }
+        assert!(matches!(
+            sgvcs.load_commit(&first).await,
+            Err(SgvcsError::CorruptObject { hash, .. }) if hash == first
+        ));
+    }
+
+    #[tokio::test]
+    async fn history_walks_lazily_back_to_the_root() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "1\n", "first").await;
+        let second: String = commit_file(&mut sgvcs, "a.txt", "2\n", "second").await;
+        let third: String = commit_file(&mut sgvcs, "a.txt", "3\n", "third").await;
+
+        let mut walked: Vec<String> = Vec::new();
+        let mut history: History = sgvcs.history("HEAD");
+        while let Some(next) = history.next().await {
+            walked.push(next.unwrap().0);
+        }
+        assert_eq!(walked, vec![third.clone(), second.clone(), first.clone()]);
+        assert!(sgvcs.history("").next().await.is_none());
+
+        // Only reaching the missing root fails, so commits are loaded one by one
+        sgvcs.objects.remove(&first).await.unwrap();
+        let mut history: History = sgvcs.history(&third[..8]);
+        assert_eq!(history.next().await.unwrap().unwrap().0, third);
+        assert_eq!(history.next().await.unwrap().unwrap().1.subject(), "second");
+        assert!(history.next().await.unwrap().is_err());
+        assert!(history.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn commit_display_lists_every_file_and_debug_keeps_all_fields() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        write_file(&sgvcs, "dir/b.txt", "b\n").await;
+        sgvcs
+            .add_file(&sgvcs.root_path.join("dir/b.txt"))
+            .await
+            .unwrap();
+        let second: String = sgvcs
+            .commit("second\n\nwith a body".to_string(), false)
+            .await
+            .unwrap();
+        let commit: CommitData = sgvcs.load_commit(&second).await.unwrap();
+
+        let full: String = commit.to_string();
+        assert!(full.starts_with("author: Tester <tester@example.com>\n"));
+        assert!(full.contains(&format!("parent: {}\n", Sgvcs::abbrev(&first))));
+        assert!(full.contains("files: 2\n"));
+        for file in &commit.files {
+            assert!(full.contains(&format!(
+                "    {} {}\n",
+                Sgvcs::abbrev(&file.hash),
+                file.path
+            )));
+        }
+        assert!(full.ends_with("    second\n    \n    with a body\n"));
+
+        let debug: String = format!("{:?}", commit);
+        for field in [
+            first.as_str(),
+            &commit.tree,
+            "dir/b.txt",
+            "tester@example.com",
+        ] {
+            assert!(debug.contains(field), "{} missing from {}", field, debug);
+        }
+    }
+
+    #[tokio::test]
+    async fn repos_in_a_newer_format_are_refused_untouched() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        let future: u32 = FORMAT_VERSION + 1;
+        sgvcs
+            .write_config(FORMAT_VERSION_KEY, &future.to_string())
+            .await
+            .unwrap();
+        let config: Vec<u8> = fs::read(&sgvcs.config_path).await.unwrap();
+        let head: Vec<u8> = fs::read(&sgvcs.head_path).await.unwrap();
+
+        let incompatible = |result: Result<_>| {
+            matches!(result, Err(SgvcsError::IncompatibleRepo { found, supported })
+                if found == future && supported == FORMAT_VERSION)
+        };
+        assert!(incompatible(
+            Sgvcs::open_at(&sgvcs.root_path).await.map(|_| ())
+        ));
+        assert!(incompatible(sgvcs.init().await.map(|_| ())));
+        assert_eq!(fs::read(&sgvcs.config_path).await.unwrap(), config);
+        assert_eq!(fs::read(&sgvcs.head_path).await.unwrap(), head);
+    }
+
+    #[tokio::test]
+    async fn init_migrates_a_repo_in_an_older_format() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let commit: String = commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        // Version 1 stored objects flat and HEAD as a raw hash
+        let layout: FsObjectStore = FsObjectStore::new(sgvcs.objects.dir().unwrap().to_path_buf());
+        let flat: PathBuf = layout.dir().unwrap().join(&commit);
+        fs::rename(layout.object_path(&commit), &flat)
+            .await
+            .unwrap();
+        fs::write(&sgvcs.head_path, &commit).await.unwrap();
+        fs::remove_file(sgvcs.heads_path.join(DEFAULT_BRANCH))
+            .await
+            .unwrap();
+        sgvcs.write_config(FORMAT_VERSION_KEY, "1").await.unwrap();
+
+        let reopened: Sgvcs = Sgvcs::open_at(&sgvcs.root_path).await.unwrap();
+        reopened.set_output(std::io::sink());
+        reopened.init().await.unwrap();
+        assert_eq!(
+            reopened.stored_format_version().await.unwrap(),
+            Some(FORMAT_VERSION)
+        );
+        assert!(!flat.exists());
+        assert!(layout.object_path(&commit).is_file());
+        assert_eq!(
+            reopened.current_branch().await.as_deref(),
+            Some(DEFAULT_BRANCH)
+        );
+        assert_eq!(reopened.get_current_head().await.unwrap(), commit);
+    }
+
+    /// Makes the next atomic writes of the repo file `name` fail by putting
+    /// directories where their temporary files go, covering the next few
+    /// temporary names the handle hands out. Passing the returned paths to
+    /// [`unblock_atomic_write`] lets writes succeed again.
+    async fn block_atomic_write(sgvcs: &Sgvcs, name: &str) -> Vec<PathBuf> {
+        let next: u64 = sgvcs.temp_names.count.load(AtomicOrdering::Relaxed);
+        let mut blocked: Vec<PathBuf> = Vec::new();
+        for count in next..next + 32 {
+            let temp: PathBuf = sgvcs.repo_path.join(sgvcs.temp_names.name(name, count));
+            fs::create_dir(&temp).await.unwrap();
+            blocked.push(temp);
+        }
+        blocked
+    }
+
+    async fn unblock_atomic_write(blocked: Vec<PathBuf>) {
+        for temp in blocked {
+            fs::remove_dir(&temp).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn a_commit_failing_before_head_moves_changes_nothing() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "one\n", "first").await;
+        let path: PathBuf = write_file(&sgvcs, "a.txt", "two\n").await;
+        sgvcs.add_file(&path).await.unwrap();
+
+        let blocked: Vec<PathBuf> = block_atomic_write(&sgvcs, DEFAULT_BRANCH).await;
+        assert!(sgvcs.commit("second".to_string(), false).await.is_err());
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), first);
+        assert_eq!(sgvcs.status_report().await.unwrap().staged, vec!["a.txt"]);
+        assert!(sgvcs.fsck().await.unwrap().is_empty());
+
+        unblock_atomic_write(blocked).await;
+        let second: String = sgvcs.commit("second".to_string(), false).await.unwrap();
+        assert_eq!(sgvcs.load_commit(&second).await.unwrap().parent, first);
+    }
+
+    #[tokio::test]
+    async fn a_commit_failing_before_the_index_is_cleared_is_complete() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "one\n", "first").await;
+        let path: PathBuf = write_file(&sgvcs, "a.txt", "two\n").await;
+        sgvcs.add_file(&path).await.unwrap();
+
+        let blocked: Vec<PathBuf> = block_atomic_write(&sgvcs, "index").await;
+        assert!(sgvcs.commit("second".to_string(), false).await.is_err());
+        let second: String = sgvcs.get_current_head().await.unwrap();
+        assert_ne!(second, first);
+        assert_eq!(sgvcs.load_commit(&second).await.unwrap().parent, first);
+        // The entries left in the index match HEAD, so nothing reads as staged
+        assert!(sgvcs.status_report().await.unwrap().staged.is_empty());
+        assert!(sgvcs.is_clean().await.unwrap());
+        assert!(sgvcs.fsck().await.unwrap().is_empty());
+
+        unblock_atomic_write(blocked).await;
+        assert!(matches!(
+            sgvcs.commit("again".to_string(), false).await,
+            Err(SgvcsError::NothingToCommit)
+        ));
+        let third: String = commit_file(&mut sgvcs, "a.txt", "three\n", "third").await;
+        assert_eq!(sgvcs.load_commit(&third).await.unwrap().parent, second);
+    }
+
+    #[tokio::test]
+    async fn archive_writes_a_commits_files_to_a_tarball() {
+        let (dir, mut sgvcs) = test_repo().await;
+        write_file(&sgvcs, "src/lib.rs", "lib\n").await;
+        sgvcs
+            .add_file(&sgvcs.root_path.join("src/lib.rs"))
+            .await
+            .unwrap();
+        let commit: String = commit_file(&mut sgvcs, "README", "readme\n", "two files").await;
+        write_file(&sgvcs, "README", "edited\n").await;
+
+        let out: PathBuf = dir.path().join("out.tar");
+        sgvcs.archive(&commit, &out).await.unwrap();
+        let tarball: Vec<u8> = fs::read(&out).await.unwrap();
+        let mut archive: tar::Archive<&[u8]> = tar::Archive::new(tarball.as_slice());
+        let mut entries: Vec<(String, String)> = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry: tar::Entry<&[u8]> = entry.unwrap();
+            let path: String = entry.path().unwrap().to_string_lossy().to_string();
+            let mut contents: String = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            entries.push((path, contents));
+        }
+        assert_eq!(
+            entries,
+            vec![
+                ("README".to_string(), "readme\n".to_string()),
+                ("src/lib.rs".to_string(), "lib\n".to_string()),
+            ]
+        );
+        assert_eq!(read_file(&sgvcs, "README").await, "edited\n");
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), commit);
+    }
+
+    #[tokio::test]
+    async fn list_files_gives_the_sorted_paths_of_a_commit() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        assert!(sgvcs.list_files("HEAD").await.unwrap().is_empty());
+        let first: String = commit_file(&mut sgvcs, "zeta.txt", "z\n", "first").await;
+        for name in ["dir/b.txt", "alpha.txt"] {
+            let path: PathBuf = write_file(&sgvcs, name, name).await;
+            sgvcs.add_file(&path).await.unwrap();
+        }
+        sgvcs.commit("second".to_string(), false).await.unwrap();
+
+        let all: Vec<PathBuf> = ["alpha.txt", "dir/b.txt", "zeta.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(sgvcs.list_files("").await.unwrap(), all);
+        assert_eq!(sgvcs.list_files("HEAD").await.unwrap(), all);
+        assert_eq!(
+            sgvcs.list_files(&first).await.unwrap(),
+            vec![PathBuf::from("zeta.txt")]
+        );
+    }
+
+    /// Paths and contents recorded in `commit`, sorted by path.
+    async fn committed_files(sgvcs: &Sgvcs, commit: &str) -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = Vec::new();
+        for file in sgvcs.load_commit(commit).await.unwrap().files {
+            let content: Vec<u8> = sgvcs.read_object(&file.hash).await.unwrap();
+            files.push((file.path, String::from_utf8(content).unwrap()));
+        }
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn amend_folds_staged_changes_into_the_last_commit() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        commit_file(&mut sgvcs, "b.txt", "b\n", "second").await;
+        let added: PathBuf = write_file(&sgvcs, "c.txt", "c\n").await;
+        sgvcs.add_file(&added).await.unwrap();
+
+        let amended: String = sgvcs.amend(None).await.unwrap();
+        let commit: CommitData = sgvcs.load_commit(&amended).await.unwrap();
+        assert_eq!(commit.parent, first);
+        assert_eq!(commit.message(), "second");
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), amended);
+        assert!(sgvcs.read_index().await.unwrap().is_empty());
+        assert_eq!(
+            committed_files(&sgvcs, &amended).await,
+            vec![
+                ("a.txt".to_string(), "a\n".to_string()),
+                ("b.txt".to_string(), "b\n".to_string()),
+                ("c.txt".to_string(), "c\n".to_string()),
+            ]
+        );
+
+        // A file missing from disk stays until its deletion is staged
+        fs::remove_file(sgvcs.root_path.join("b.txt"))
+            .await
+            .unwrap();
+        let kept: String = sgvcs.amend(Some("message only".to_string())).await.unwrap();
+        assert_eq!(committed_files(&sgvcs, &kept).await.len(), 3);
+        sgvcs
+            .rm(&sgvcs.root_path.join("a.txt"), false)
+            .await
+            .unwrap();
+        sgvcs
+            .rm(&sgvcs.root_path.join("c.txt"), false)
+            .await
+            .unwrap();
+        let again: String = sgvcs.amend(Some("reworded".to_string())).await.unwrap();
+        assert_eq!(sgvcs.load_commit(&again).await.unwrap().parent, first);
+        assert_eq!(
+            committed_files(&sgvcs, &again).await,
+            vec![("b.txt".to_string(), "b\n".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn checkout_new_branch_makes_head_follow_it() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let head: String = commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        write_file(&sgvcs, "a.txt", "local edit\n").await;
+
+        sgvcs.checkout_new_branch("feature").await.unwrap();
+        assert_eq!(
+            fs::read_to_string(&sgvcs.head_path).await.unwrap(),
+            "ref: refs/heads/feature"
+        );
+        assert_eq!(
+            fs::read_to_string(sgvcs.heads_path.join("feature"))
+                .await
+                .unwrap(),
+            head
+        );
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "local edit\n");
+
+        sgvcs.checkout(DEFAULT_BRANCH, true).await.unwrap();
+        assert!(matches!(
+            sgvcs.checkout_new_branch("feature").await,
+            Err(SgvcsError::AlreadyExists(_))
+        ));
+        assert_eq!(
+            sgvcs.current_branch().await.as_deref(),
+            Some(DEFAULT_BRANCH)
+        );
+    }
+
+    #[tokio::test]
+    async fn indentation_only_commits_diff_empty_when_ignoring_whitespace() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.rs", "if x {\n  y();\n}\n", "first").await;
+        let second: String =
+            commit_file(&mut sgvcs, "a.rs", "if x {\n\ty();\n}\n", "reindent").await;
+        let options: DiffOptions = DiffOptions {
+            ignore_whitespace: true,
+            ..DiffOptions::default()
+        };
+
+        assert_eq!(sgvcs.diff_commit_with(&second, &options).await.unwrap(), "");
+        assert_eq!(
+            sgvcs
+                .diff_commits_with(&first, &second, &options)
+                .await
+                .unwrap(),
+            ""
+        );
+        assert!(sgvcs
+            .diff_commit(&second)
+            .await
+            .unwrap()
+            .contains("-  y();\n+\ty();\n"));
+    }
+
+    #[tokio::test]
+    async fn staged_diff_compares_the_index_with_head() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "a.txt", "one\ntwo\n", "first").await;
+        let a: PathBuf = write_file(&sgvcs, "a.txt", "one\n2\n").await;
+        sgvcs.add_file(&a).await.unwrap();
+        let new: PathBuf = write_file(&sgvcs, "new.txt", "fresh\n").await;
+        sgvcs.add_file(&new).await.unwrap();
+        // Unstaged edits are not part of it
+        write_file(&sgvcs, "a.txt", "unstaged\n").await;
+
+        let diff: String = sgvcs.staged_diff().await.unwrap();
+        assert!(diff.contains("--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+2\n"));
+        assert!(diff.contains("--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,1 @@\n+fresh\n"));
+        assert!(!diff.contains("unstaged"));
+    }
+
+    #[tokio::test]
+    async fn init_reports_whether_it_created_the_repo() {
+        let dir: TempDir = tempfile::tempdir().unwrap();
+        let root: PathBuf = fs::canonicalize(dir.path()).await.unwrap();
+        let mut sgvcs: Sgvcs = Sgvcs::at(root);
+        sgvcs.set_output(std::io::sink());
+        assert_eq!(sgvcs.init().await.unwrap(), InitOutcome::Created);
+        sgvcs.set_config("user.name", "Tester").await.unwrap();
+        sgvcs
+            .set_config("user.email", "tester@example.com")
+            .await
+            .unwrap();
+        commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        sgvcs.checkout_new_branch("feature").await.unwrap();
+        let staged: PathBuf = write_file(&sgvcs, "b.txt", "b\n").await;
+        sgvcs.add_file(&staged).await.unwrap();
+        let head: Vec<u8> = fs::read(&sgvcs.head_path).await.unwrap();
+        let index: Vec<u8> = fs::read(&sgvcs.index_path).await.unwrap();
+
+        assert_eq!(sgvcs.init().await.unwrap(), InitOutcome::AlreadyExists);
+        assert_eq!(fs::read(&sgvcs.head_path).await.unwrap(), head);
+        assert_eq!(fs::read(&sgvcs.index_path).await.unwrap(), index);
+        assert_eq!(
+            sgvcs.get_config("user.name").await.unwrap().as_deref(),
+            Some("Tester")
+        );
+    }
+
+    #[tokio::test]
+    async fn every_object_is_stored_at_its_object_path() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        write_file(&sgvcs, "dir/b.txt", "b\n").await;
+        sgvcs
+            .add_file(&sgvcs.root_path.join("dir/b.txt"))
+            .await
+            .unwrap();
+        commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+
+        let layout: FsObjectStore = FsObjectStore::new(sgvcs.objects.dir().unwrap().to_path_buf());
+        let mut expected: Vec<PathBuf> = sgvcs
+            .object_hashes()
+            .await
+            .unwrap()
+            .iter()
+            .map(|hash| layout.object_path(hash))
+            .collect();
+        let mut stored: Vec<PathBuf> = Vec::new();
+        for shard in std::fs::read_dir(layout.dir().unwrap()).unwrap() {
+            for object in std::fs::read_dir(shard.unwrap().path()).unwrap() {
+                stored.push(object.unwrap().path());
+            }
+        }
+        expected.sort();
+        stored.sort();
+        // Two blobs, two trees and the commit
+        assert_eq!(stored.len(), 5);
+        assert_eq!(stored, expected);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_tracked_file_can_be_committed() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "keep.txt", "keep\n", "first").await;
+        let with_file: String = commit_file(&mut sgvcs, "gone.txt", "gone\n", "second").await;
+        let gone: PathBuf = sgvcs.root_path.join("gone.txt");
+        fs::remove_file(&gone).await.unwrap();
+        assert_eq!(
+            sgvcs.status_report().await.unwrap().deleted,
+            vec!["gone.txt"]
+        );
+
+        sgvcs.stage_deletion(&gone).await.unwrap();
+        let report: StatusReport = sgvcs.status_report().await.unwrap();
+        assert_eq!(report.staged, vec!["gone.txt"]);
+        assert!(report.deleted.is_empty());
+        let without: String = sgvcs.commit("delete".to_string(), false).await.unwrap();
+        assert_eq!(
+            sgvcs.list_files(&without).await.unwrap(),
+            vec![PathBuf::from("keep.txt")]
+        );
+
+        sgvcs.checkout(&without, false).await.unwrap();
+        assert!(!gone.exists());
+        sgvcs.checkout(&with_file, false).await.unwrap();
+        assert_eq!(read_file(&sgvcs, "gone.txt").await, "gone\n");
+        sgvcs.checkout(DEFAULT_BRANCH, true).await.unwrap();
+        assert!(!gone.exists());
+
+        // add_dir notices deletions on its own
+        fs::remove_file(sgvcs.root_path.join("keep.txt"))
+            .await
+            .unwrap();
+        sgvcs.add_dir(&sgvcs.root_path.clone()).await.unwrap();
+        let last: String = sgvcs.commit("delete all".to_string(), false).await.unwrap();
+        assert!(sgvcs.list_files(&last).await.unwrap().is_empty());
+    }
+
+    /// Stages `contents` as `name` and returns the hash it was staged as.
+    async fn staged_hash(sgvcs: &mut Sgvcs, name: &str, contents: &[u8]) -> String {
+        let path: PathBuf = sgvcs.root_path.join(name);
+        fs::write(&path, contents).await.unwrap();
+        sgvcs.add_file(&path).await.unwrap();
+        let key: String = Sgvcs::path_key(Path::new(name)).unwrap();
+        let index: Vec<IndexData> = sgvcs.read_index().await.unwrap();
+        index
+            .into_iter()
+            .find(|entry| entry.path == key)
+            .unwrap()
+            .hash
+    }
+
+    #[tokio::test]
+    async fn autocrlf_stores_lf_and_crlf_text_alike() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let lf: String = staged_hash(&mut sgvcs, "lf.txt", b"one\ntwo\n").await;
+        let crlf: String = staged_hash(&mut sgvcs, "crlf.txt", b"one\r\ntwo\r\n").await;
+        assert_ne!(lf, crlf);
+
+        for setting in ["input", "true"] {
+            sgvcs.set_config(AUTOCRLF_KEY, setting).await.unwrap();
+            assert_eq!(
+                staged_hash(&mut sgvcs, "crlf.txt", b"one\r\ntwo\r\n").await,
+                lf
+            );
+            assert_eq!(staged_hash(&mut sgvcs, "lf.txt", b"one\ntwo\n").await, lf);
+            // Binary content is stored exactly as it is
+            let binary: &[u8] = b"\0one\r\n";
+            assert_eq!(
+                staged_hash(&mut sgvcs, "blob.bin", binary).await,
+                sgvcs.hash_object(binary, false).await.unwrap()
+            );
+        }
+        assert!(sgvcs.set_config(AUTOCRLF_KEY, "sometimes").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn autocrlf_true_checks_text_out_with_crlf() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        sgvcs.set_config(AUTOCRLF_KEY, "true").await.unwrap();
+        staged_hash(&mut sgvcs, "a.txt", b"one\r\ntwo\n").await;
+        staged_hash(&mut sgvcs, "blob.bin", b"\0x\ny").await;
+        let commit: String = sgvcs.commit("first".to_string(), false).await.unwrap();
+        fs::remove_file(sgvcs.root_path.join("a.txt"))
+            .await
+            .unwrap();
+        fs::remove_file(sgvcs.root_path.join("blob.bin"))
+            .await
+            .unwrap();
+
+        sgvcs.checkout(&commit, true).await.unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "one\r\ntwo\r\n");
+        assert_eq!(
+            fs::read(sgvcs.root_path.join("blob.bin")).await.unwrap(),
+            b"\0x\ny"
+        );
+        assert!(sgvcs.is_clean().await.unwrap());
+
+        sgvcs.set_config(AUTOCRLF_KEY, "input").await.unwrap();
+        sgvcs
+            .restore_file(&sgvcs.root_path.join("a.txt"), &commit)
+            .await
+            .unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "one\ntwo\n");
+    }
+
+    #[tokio::test]
+    async fn a_held_index_lock_blocks_other_writers() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let path: PathBuf = write_file(&sgvcs, "a.txt", "a\n").await;
+        let lock_path: PathBuf = sgvcs.repo_path.join(INDEX_LOCK_FILE);
+
+        let held: IndexLock = sgvcs.lock_index().await.unwrap();
+        assert!(matches!(sgvcs.lock_index().await, Err(SgvcsError::Locked(p)) if p == lock_path));
+        assert!(matches!(
+            sgvcs.add_file(&path).await,
+            Err(SgvcsError::Locked(_))
+        ));
+        assert!(matches!(
+            sgvcs.commit("first".to_string(), true).await,
+            Err(SgvcsError::Locked(_))
+        ));
+        assert!(sgvcs.read_index().await.unwrap().is_empty());
+        drop(held);
+        assert!(!lock_path.exists());
+
+        sgvcs.add_file(&path).await.unwrap();
+        sgvcs.commit("first".to_string(), false).await.unwrap();
+        // Failing while holding the lock still releases it
+        assert!(sgvcs.commit("again".to_string(), false).await.is_err());
+        assert!(!lock_path.exists());
+    }
+
+    #[tokio::test]
+    async fn switch_moves_head_between_branches() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let main_tip: String = commit_file(&mut sgvcs, "a.txt", "main\n", "first").await;
+        sgvcs.checkout_new_branch("feature").await.unwrap();
+        let feature_tip: String = commit_file(&mut sgvcs, "a.txt", "feature\n", "second").await;
+
+        sgvcs.switch(DEFAULT_BRANCH, false).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(&sgvcs.head_path).await.unwrap(),
+            format!("{}{}", HEAD_REF_PREFIX, DEFAULT_BRANCH)
+        );
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), main_tip);
+        // Only HEAD moved, so the feature content shows up as a change
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "feature\n");
+        assert_eq!(sgvcs.status_report().await.unwrap().modified, vec!["a.txt"]);
+
+        sgvcs.switch("feature", false).await.unwrap();
+        assert!(sgvcs.is_clean().await.unwrap());
+        sgvcs.switch(DEFAULT_BRANCH, true).await.unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "main\n");
+        sgvcs.switch("feature", true).await.unwrap();
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), feature_tip);
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "feature\n");
+
+        assert!(matches!(
+            sgvcs.switch("missing", false).await,
+            Err(SgvcsError::NotFound(_))
+        ));
+        assert_eq!(sgvcs.current_branch().await.as_deref(), Some("feature"));
+    }
+
+    #[tokio::test]
+    async fn commits_on_a_detached_head_can_be_saved_on_a_branch() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "1\n", "first").await;
+        let main_tip: String = commit_file(&mut sgvcs, "a.txt", "2\n", "second").await;
+        sgvcs.checkout(&first, false).await.unwrap();
+        assert_eq!(fs::read_to_string(&sgvcs.head_path).await.unwrap(), first);
+        assert!(sgvcs.is_detached().await);
+
+        let output: Arc<Mutex<Vec<u8>>> = capture_output(&sgvcs);
+        let detached: String = commit_file(&mut sgvcs, "b.txt", "b\n", "detached").await;
+        assert!(captured(&output).contains("HEAD is detached"));
+        assert_eq!(
+            fs::read_to_string(&sgvcs.head_path).await.unwrap(),
+            detached
+        );
+        assert_eq!(sgvcs.branch_tip(DEFAULT_BRANCH).await.unwrap(), main_tip);
+        let log: Vec<LogEntry> = sgvcs.log_entries(None).await.unwrap();
+        let hashes: Vec<&str> = log.iter().map(|entry| entry.hash.as_str()).collect();
+        assert_eq!(hashes, vec![detached.as_str(), first.as_str()]);
+
+        sgvcs.branch("saved").await.unwrap();
+        sgvcs.checkout(DEFAULT_BRANCH, true).await.unwrap();
+        assert_eq!(sgvcs.branch_tip("saved").await.unwrap(), detached);
+        sgvcs.checkout("saved", false).await.unwrap();
+        assert_eq!(read_file(&sgvcs, "b.txt").await, "b\n");
+        assert!(!sgvcs.is_detached().await);
+    }
+
+    /// `main` and `feature` both change `a.txt`; `feature` also adds a file.
+    /// Leaves HEAD on `main` and returns the tip of `feature`.
+    async fn conflicting_branches(sgvcs: &mut Sgvcs) -> String {
+        commit_file(sgvcs, "a.txt", "base\n", "base").await;
+        sgvcs.checkout_new_branch("feature").await.unwrap();
+        let new: PathBuf = write_file(sgvcs, "new.txt", "new\n").await;
+        sgvcs.add_file(&new).await.unwrap();
+        let feature: String = commit_file(sgvcs, "a.txt", "feature\n", "feature").await;
+        sgvcs.checkout(DEFAULT_BRANCH, true).await.unwrap();
+        commit_file(sgvcs, "a.txt", "main\n", "main").await;
+        feature
+    }
+
+    #[tokio::test]
+    async fn merge_abort_restores_the_state_before_the_merge() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let feature: String = conflicting_branches(&mut sgvcs).await;
+        let head: String = sgvcs.get_current_head().await.unwrap();
+
+        let result: MergeResult = sgvcs.merge("feature").await.unwrap();
+        assert_eq!(result.commit, None);
+        assert_eq!(result.conflicts, vec!["a.txt"]);
+        assert_eq!(sgvcs.merge_head().await.unwrap(), Some(feature));
+        assert!(read_file(&sgvcs, "a.txt").await.contains("<<<<<<<"));
+        assert!(sgvcs.merge("feature").await.is_err());
+
+        sgvcs.merge_abort().await.unwrap();
+        assert_eq!(read_file(&sgvcs, "a.txt").await, "main\n");
+        assert!(!sgvcs.root_path.join("new.txt").exists());
+        assert!(sgvcs.read_index().await.unwrap().is_empty());
+        assert_eq!(sgvcs.merge_head().await.unwrap(), None);
+        assert!(!sgvcs.repo_path.join(MERGE_HEAD_FILE).exists());
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), head);
+        assert!(sgvcs.is_clean().await.unwrap());
+        assert!(sgvcs.merge_abort().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn committing_a_resolved_merge_records_both_parents() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let feature: String = conflicting_branches(&mut sgvcs).await;
+        let head: String = sgvcs.get_current_head().await.unwrap();
+        sgvcs.merge("feature").await.unwrap();
+
+        let merged: String = commit_file(&mut sgvcs, "a.txt", "both\n", "merge").await;
+        let commit: CommitData = sgvcs.load_commit(&merged).await.unwrap();
+        assert_eq!(commit.parent_hashes(), vec![head, feature]);
+        assert_eq!(sgvcs.merge_head().await.unwrap(), None);
+        assert_eq!(
+            committed_files(&sgvcs, &merged).await,
+            vec![
+                ("a.txt".to_string(), "both\n".to_string()),
+                ("new.txt".to_string(), "new\n".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn log_and_status_json_deserialize_back() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        commit_file(&mut sgvcs, "b.txt", "b\n", "second\n\nbody").await;
+        let staged: PathBuf = write_file(&sgvcs, "c.txt", "c\n").await;
+        sgvcs.add_file(&staged).await.unwrap();
+        write_file(&sgvcs, "a.txt", "edited\n").await;
+        write_file(&sgvcs, "d.txt", "d\n").await;
+
+        let log: Vec<LogEntry> = serde_json::from_str(&sgvcs.log_json().await.unwrap()).unwrap();
+        assert_eq!(log, sgvcs.log_entries(None).await.unwrap());
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].message, "second\n\nbody");
+        assert_eq!(log[1].parent, "");
+
+        let status: StatusReport =
+            serde_json::from_str(&sgvcs.status_json().await.unwrap()).unwrap();
+        assert_eq!(
+            status,
+            StatusReport {
+                staged: vec!["c.txt".to_string()],
+                modified: vec!["a.txt".to_string()],
+                deleted: Vec::new(),
+                untracked: vec!["d.txt".to_string()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn a_malformed_index_is_reported_as_corrupt() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let path: PathBuf = write_file(&sgvcs, "a.txt", "a\n").await;
+        sgvcs.add_file(&path).await.unwrap();
+        let good: String = fs::read_to_string(&sgvcs.index_path).await.unwrap();
+
+        let corrupt = |result: Result<()>| matches!(result, Err(SgvcsError::CorruptIndex(_)));
+        for bad in [&good[..good.len() / 2], "garbage", "", "{\"path\": 1}"] {
+            fs::write(&sgvcs.index_path, bad).await.unwrap();
+            assert!(corrupt(sgvcs.verify_index().await));
+            assert!(corrupt(sgvcs.add_file(&path).await));
+            assert!(corrupt(sgvcs.status_report().await.map(|_| ())));
+            assert!(corrupt(
+                sgvcs.commit("x".to_string(), false).await.map(|_| ())
+            ));
+        }
+
+        // A blob that is staged but not stored makes a stale index
+        fs::write(&sgvcs.index_path, &good).await.unwrap();
+        sgvcs.verify_index().await.unwrap();
+        let blob: String = sgvcs.read_index().await.unwrap()[0].hash.clone();
+        sgvcs.objects.remove(&blob).await.unwrap();
+        match sgvcs.verify_index().await {
+            Err(SgvcsError::CorruptIndex(reason)) => assert!(reason.contains(&blob)),
+            other => panic!("expected a corrupt index, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_commit_is_an_error_not_an_empty_file_list() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        let second: String = commit_file(&mut sgvcs, "b.txt", "b\n", "second").await;
+        let tree: String = sgvcs.load_commit(&second).await.unwrap().tree;
+        sgvcs.objects.remove(&tree).await.unwrap();
+
+        let path: PathBuf = write_file(&sgvcs, "c.txt", "c\n").await;
+        sgvcs.add_file(&path).await.unwrap();
+        assert!(sgvcs.commit("third".to_string(), false).await.is_err());
+        assert!(sgvcs.amend(None).await.is_err());
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), second);
+        assert!(sgvcs.checkout(&first, true).await.is_err());
+        assert!(sgvcs.reset(&first, ResetMode::Hard).await.is_err());
+        assert_eq!(read_file(&sgvcs, "b.txt").await, "b\n");
+    }
+
+    #[tokio::test]
+    async fn amend_is_refused_during_a_merge() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        conflicting_branches(&mut sgvcs).await;
+        let head: String = sgvcs.get_current_head().await.unwrap();
+        sgvcs.merge("feature").await.unwrap();
+
+        assert!(matches!(
+            sgvcs.amend(Some("oops".to_string())).await,
+            Err(SgvcsError::InvalidInput(_))
+        ));
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), head);
+        assert!(sgvcs.merge_head().await.unwrap().is_some());
+    }
+
+    /// Six commits on `main` where `state.txt` reads "bad" from the fifth
+    /// on. Returns the commits, oldest first.
+    async fn bisect_history(sgvcs: &mut Sgvcs) -> Vec<String> {
+        let mut commits: Vec<String> = Vec::new();
+        for n in 0..6 {
+            let state: &str = if n >= 4 { "bad" } else { "good" };
+            let number: PathBuf = write_file(sgvcs, "n.txt", &format!("{}\n", n)).await;
+            let path: PathBuf = write_file(sgvcs, "state.txt", state).await;
+            sgvcs.add_file(&number).await.unwrap();
+            sgvcs.add_file(&path).await.unwrap();
+            commits.push(sgvcs.commit(format!("commit {}", n), false).await.unwrap());
+        }
+        commits
+    }
+
+    #[tokio::test]
+    async fn bisect_converges_on_the_first_bad_commit() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let commits: Vec<String> = bisect_history(&mut sgvcs).await;
+
+        let mut step: BisectStep = sgvcs.bisect_start(&commits[0], &commits[5]).await.unwrap();
+        let mut tested: usize = 0;
+        let found: String = loop {
+            match step {
+                BisectStep::Testing { commit, .. } => {
+                    assert_eq!(sgvcs.get_current_head().await.unwrap(), commit);
+                    tested += 1;
+                    step = if read_file(&sgvcs, "state.txt").await == "bad" {
+                        sgvcs.bisect_bad().await.unwrap()
+                    } else {
+                        sgvcs.bisect_good().await.unwrap()
+                    };
+                }
+                BisectStep::Found(commit) => break commit,
+            }
+        };
+        assert_eq!(found, commits[4]);
+        assert!(tested <= 3);
+
+        sgvcs.bisect_reset().await.unwrap();
+        assert!(!sgvcs.bisect_path().exists());
+        assert_eq!(
+            sgvcs.current_branch().await.as_deref(),
+            Some(DEFAULT_BRANCH)
+        );
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), commits[5]);
+    }
+
+    #[tokio::test]
+    async fn bisect_on_a_dirty_tree_leaves_no_state_behind() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let commits: Vec<String> = bisect_history(&mut sgvcs).await;
+        write_file(&sgvcs, "n.txt", "local edit\n").await;
+
+        assert!(matches!(
+            sgvcs.bisect_start(&commits[0], &commits[5]).await,
+            Err(SgvcsError::InvalidInput(_))
+        ));
+        assert!(!sgvcs.bisect_path().exists());
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), commits[5]);
+
+        write_file(&sgvcs, "n.txt", "5\n").await;
+        let BisectStep::Testing { commit, .. } =
+            sgvcs.bisect_start(&commits[0], &commits[5]).await.unwrap()
+        else {
+            panic!("expected a commit to test");
+        };
+
+        // A step that cannot check out its next commit keeps the saved state
+        let saved: String = fs::read_to_string(sgvcs.bisect_path()).await.unwrap();
+        write_file(&sgvcs, "n.txt", "local edit\n").await;
+        assert!(sgvcs.bisect_good().await.is_err());
+        assert_eq!(
+            fs::read_to_string(sgvcs.bisect_path()).await.unwrap(),
+            saved
+        );
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), commit);
+        sgvcs.checkout(&commit, true).await.unwrap();
+        assert!(sgvcs.bisect_good().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn crafted_trees_and_stashes_cannot_reach_outside_the_work_tree() {
+        let dir: TempDir = tempfile::tempdir().unwrap();
+        let root: PathBuf = fs::canonicalize(dir.path()).await.unwrap().join("repo");
+        fs::create_dir(&root).await.unwrap();
+        let mut sgvcs: Sgvcs = Sgvcs::at(root);
+        sgvcs.set_output(std::io::sink());
+        sgvcs.init().await.unwrap();
+        sgvcs.set_config("user.name", "Tester").await.unwrap();
+        sgvcs
+            .set_config("user.email", "tester@example.com")
+            .await
+            .unwrap();
+        let first: String = commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        let outside: PathBuf = dir.path().join("outside.txt");
+        fs::write(&outside, "keep\n").await.unwrap();
+        // Trees written by this crate never hold `..`, so build one by hand
+        let blob: String = sgvcs.hash_object(b"escaped\n", true).await.unwrap();
+        let mut root_node: BTreeMap<String, TreeNode> = BTreeMap::new();
+        for file in sgvcs.head_files().await.unwrap() {
+            root_node.insert(file.path, TreeNode::Blob(file.hash, file.mode));
+        }
+        root_node.insert(
+            "..".to_string(),
+            TreeNode::Dir(BTreeMap::from([(
+                "outside.txt".to_string(),
+                TreeNode::Blob(blob, DEFAULT_MODE),
+            )])),
+        );
+        let mut objects: Vec<TreeObject> = Vec::new();
+        let tree: String = sgvcs
+            .build_tree(&root_node, canonical_json, &mut objects)
+            .unwrap();
+        for (hash, content) in objects {
+            sgvcs
+                .write_object(&hash, ObjectType::Tree, &content)
+                .await
+                .unwrap();
+        }
+        let mut commit: CommitData = sgvcs.load_commit(&first).await.unwrap();
+        commit.parent = first.clone();
+        commit.tree = tree;
+        let bytes: Vec<u8> = commit.canonical_bytes().unwrap();
+        let crafted: String = sgvcs.hash(ObjectType::Commit, &bytes);
+        sgvcs
+            .write_object(&crafted, ObjectType::Commit, &bytes)
+            .await
+            .unwrap();
+        let kept = || async { fs::read_to_string(&outside).await.unwrap() == "keep\n" };
+
+        // Writing the crafted tree out
+        assert!(sgvcs.checkout(&crafted, true).await.is_err());
+        assert!(sgvcs.reset(&crafted, ResetMode::Hard).await.is_err());
+        assert!(kept().await);
+
+        // Deleting its files when moving away from it
+        sgvcs.update_head(&crafted, "test").await.unwrap();
+        assert!(sgvcs.checkout(&first, true).await.is_err());
+        assert!(sgvcs.reset(&first, ResetMode::Hard).await.is_err());
+        assert!(kept().await);
+
+        // Applying a stash that names it
+        sgvcs.update_head(&first, "test").await.unwrap();
+        let entry: StashEntry = StashEntry {
+            base: first.clone(),
+            message: "crafted".to_string(),
+            index: Vec::new(),
+            worktree: vec![IndexData::deletion("../outside.txt".to_string())],
+        };
+        sgvcs.write_stash(&[entry]).await.unwrap();
+        assert!(sgvcs.stash_pop().await.is_err());
+        assert!(kept().await);
+    }
+
+    #[tokio::test]
+    async fn nested_files_survive_a_checkout_round_trip() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "top.txt", "top\n", "first").await;
+        let nested: String =
+            commit_file(&mut sgvcs, "src/a/b.rs", "fn main() {}\n", "nested").await;
+        assert_eq!(
+            committed_files(&sgvcs, &nested).await,
+            vec![
+                ("src/a/b.rs".to_string(), "fn main() {}\n".to_string()),
+                ("top.txt".to_string(), "top\n".to_string()),
+            ]
+        );
+
+        sgvcs.checkout(&first, true).await.unwrap();
+        assert!(!sgvcs.root_path.join("src/a/b.rs").exists());
+        sgvcs.checkout(&nested, true).await.unwrap();
+        assert_eq!(read_file(&sgvcs, "src/a/b.rs").await, "fn main() {}\n");
+        assert_eq!(read_file(&sgvcs, "top.txt").await, "top\n");
+    }
+
+    #[tokio::test]
+    async fn damaged_compressed_objects_are_corrupt_not_read_verbatim() {
+        let (_dir, sgvcs) = test_repo().await;
+        let hash: String = sgvcs.hash_object(b"hello\n", true).await.unwrap();
+        let path: PathBuf =
+            FsObjectStore::new(sgvcs.objects.dir().unwrap().to_path_buf()).object_path(&hash);
+        let stored: Vec<u8> = fs::read(&path).await.unwrap();
+
+        fs::write(&path, &stored[..stored.len() / 2]).await.unwrap();
+        assert!(matches!(
+            sgvcs.cat_file(&hash).await,
+            Err(SgvcsError::CorruptObject { .. })
+        ));
+        let mut flipped: Vec<u8> = stored.clone();
+        flipped[0] ^= 0xff;
+        fs::write(&path, &flipped).await.unwrap();
+        assert!(matches!(
+            sgvcs.cat_file(&hash).await,
+            Err(SgvcsError::CorruptObject { .. })
+        ));
+
+        // Uncompressed bytes with a header are still read as they are
+        fs::write(&path, b"blob 6\0hello\n").await.unwrap();
+        assert_eq!(sgvcs.cat_file(&hash).await.unwrap(), b"hello\n");
+        // As are headerless ones from before compression, named by their bytes
+        let legacy: String = sgvcs.hash_stored(b"legacy\n");
+        let legacy_path: PathBuf =
+            FsObjectStore::new(sgvcs.objects.dir().unwrap().to_path_buf()).object_path(&legacy);
+        fs::create_dir_all(legacy_path.parent().unwrap())
+            .await
+            .unwrap();
+        fs::write(&legacy_path, b"legacy\n").await.unwrap();
+        assert_eq!(sgvcs.cat_file(&legacy).await.unwrap(), b"legacy\n");
+
+        fs::write(&path, b"tampered").await.unwrap();
+        assert_eq!(
+            sgvcs.fsck().await.unwrap(),
+            vec![FsckError::HashMismatch {
+                hash: hash.clone(),
+                actual: sgvcs.hash_stored(b"tampered"),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_head_is_an_error_not_an_unborn_branch() {
+        let (_dir, mut sgvcs) = test_repo().await;
+        let first: String = commit_file(&mut sgvcs, "a.txt", "a\n", "first").await;
+        let branch: PathBuf = sgvcs.heads_path.join(DEFAULT_BRANCH);
+        fs::remove_file(&branch).await.unwrap();
+        fs::create_dir(&branch).await.unwrap();
+        assert!(sgvcs.get_current_head().await.is_err());
+        // Committing would otherwise start a new root commit
+        write_file(&sgvcs, "b.txt", "b\n").await;
+        sgvcs
+            .add_file(&sgvcs.root_path.join("b.txt"))
+            .await
+            .unwrap();
+        assert!(sgvcs.commit("second".to_string(), false).await.is_err());
+
+        fs::remove_dir(&branch).await.unwrap();
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), "");
+        fs::write(&branch, &first).await.unwrap();
+        assert_eq!(sgvcs.get_current_head().await.unwrap(), first);
     }
 }